@@ -0,0 +1,42 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+// The serialized FileDescriptorSet and the Rust file exposing it as `FILE_DESCRIPTOR_SET`
+const DESCRIPTOR_SET_FILE: &str = "file_descriptor_set.bin";
+const DESCRIPTOR_SET_RS_FILE: &str = "file_descriptor_set.rs";
+
+// Compiles the .proto files into a descriptor set holding them along with all their imports,
+// e.g. the cloudstate and google.api annotations, as the proxy needs every one of them.
+// It's exposed by `file_descriptor_set.rs` in the output directory, to be included by the crate:
+// `include!(concat!(env!("OUT_DIR"), "/file_descriptor_set.rs"));`
+pub fn write_file_descriptor_set<P: AsRef<Path>>(protos: &[P], includes: &[P], out_dir: &Path) -> io::Result<()> {
+    let descriptor_set = out_dir.join(DESCRIPTOR_SET_FILE);
+
+    let mut cmd = Command::new(prost_build::protoc());
+    cmd.arg("--include_imports")
+        .arg("-o")
+        .arg(&descriptor_set);
+    for include in includes {
+        cmd.arg("-I").arg(include.as_ref());
+    }
+    // The well known types, after the user includes so they can be overridden
+    cmd.arg("-I").arg(prost_build::protoc_include());
+    for proto in protos {
+        cmd.arg(proto.as_ref());
+    }
+
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("protoc failed: {}", String::from_utf8_lossy(&output.stderr))));
+    }
+
+    let descriptor_set = descriptor_set.canonicalize()?;
+    let descriptor_set = descriptor_set.to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Expected utf8 out_dir"))?;
+    fs::write(
+        out_dir.join(DESCRIPTOR_SET_RS_FILE),
+        format!("pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!({:?});\n", descriptor_set),
+    )
+}
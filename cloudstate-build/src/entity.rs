@@ -0,0 +1,147 @@
+use cloudstate_codegen::{CommandReply, Replies, EMPTY_TYPE_URL, decode_message, encode_message};
+use heck::CamelCase;
+use proc_macro2::{Ident, Span, TokenStream};
+use prost_build::{Method, Service};
+use quote::quote;
+
+// The entity types of the service, see `configure`
+pub(crate) fn generate(service: &Service) -> TokenStream {
+    let command_name = Ident::new(&format!("{}Command", service.name), Span::call_site());
+    let response_name = Ident::new(&format!("{}Response", service.name), Span::call_site());
+    let handler_name = Ident::new(&format!("{}Handler", service.name), Span::call_site());
+
+    let methods: Vec<_> = service.methods.iter().map(MethodTypes::new).collect();
+
+    let command_variants = methods.iter().map(|m| {
+        let (variant, input) = (&m.variant, &m.input);
+        quote!(#variant(#input))
+    });
+
+    // The methods are known by name, so a message taken by several of them is decoded into the right variant
+    let decode_command_items = methods.iter().map(|m| {
+        let proto_name = &m.proto_name;
+        let input_type_url = &m.input_type_url;
+        let decode = decode_message(&command_name, &m.variant, &m.input_type_url, &m.input);
+        quote!((#proto_name, #input_type_url) => #decode,)
+    });
+
+    let decode_command = quote!(
+        #[allow(unused_variables)]
+        fn decode_command(command_name: &str, type_url: &str, bytes: impl ::cloudstate_core::bytes::Buf) -> Result<Self, ::cloudstate_core::MessageError> {
+            match (command_name, type_url) {
+                #(#decode_command_items)*
+                _ => Err(::cloudstate_core::MessageError::unknown_command(command_name, type_url)),
+            }
+        }
+    );
+
+    let command_impl = impl_any_message(&command_name, methods.iter().map(|m| (&m.variant, &m.input_type_url, &m.input)), decode_command);
+
+    let replies = Replies {
+        vis: quote!(pub),
+        attrs: quote!(#[derive(Debug, Clone, PartialEq)]),
+        command_name: &command_name,
+        reply_name: &response_name,
+        handler_name: &handler_name,
+        commands: methods.iter().map(|m| CommandReply {
+            variant: m.variant.clone(),
+            method: m.method.clone(),
+            command: m.input.clone(),
+            reply: m.output.clone(),
+            type_url: m.output_type_url.clone(),
+        }).collect(),
+    };
+    let replies = replies.generate();
+
+    quote! {
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum #command_name {
+            #(#command_variants),*
+        }
+
+        #command_impl
+
+        #replies
+    }
+}
+
+struct MethodTypes {
+    proto_name: String,
+    variant: Ident,
+    method: Ident,
+    input: syn::Type,
+    output: syn::Type,
+    input_type_url: String,
+    output_type_url: String,
+}
+
+impl MethodTypes {
+
+    fn new(method: &Method) -> MethodTypes {
+        MethodTypes {
+            proto_name: method.proto_name.clone(),
+            variant: Ident::new(&method.proto_name.to_camel_case(), Span::call_site()),
+            method: Ident::new(&method.name, Span::call_site()),
+            input: rust_type(&method.input_type),
+            output: rust_type(&method.output_type),
+            input_type_url: type_url(&method.input_proto_type),
+            output_type_url: type_url(&method.output_proto_type),
+        }
+    }
+}
+
+fn rust_type(type_path: &str) -> syn::Type {
+    syn::parse_str(type_path).unwrap_or_else(|err| panic!("Invalid message type {}: {}", type_path, err))
+}
+
+// The protobuf types are fully qualified with a leading dot
+fn type_url(proto_type: &str) -> String {
+    format!("type.googleapis.com/{}", proto_type.trim_start_matches('.'))
+}
+
+// Several variants may hold the same message, only the first one is decoded by the type URL alone
+fn impl_any_message<'a>(enum_name: &Ident,
+                        variants: impl Iterator<Item = (&'a Ident, &'a String, &'a syn::Type)>,
+                        decode_command: TokenStream) -> TokenStream {
+    let mut type_urls: Vec<&String> = vec![];
+    let mut decode_items = vec![];
+    let mut encode_items = vec![];
+
+    for (variant, type_url, message) in variants {
+        if !type_urls.contains(&type_url) {
+            let decode = decode_message(enum_name, variant, type_url, message);
+            decode_items.push(quote!(#type_url => #decode,));
+            type_urls.push(type_url);
+        }
+        encode_items.push(if type_url == EMPTY_TYPE_URL {
+            quote!(#enum_name::#variant(()) => Ok((#type_url.to_owned(), vec![])),)
+        } else {
+            let encode = encode_message(type_url);
+            quote!(#enum_name::#variant(msg) => #encode,)
+        });
+    }
+
+    quote! {
+        impl ::cloudstate_core::AnyMessage for #enum_name {
+            #[allow(unused_variables)]
+            fn decode(type_url: &str, bytes: impl ::cloudstate_core::bytes::Buf) -> Result<Self, ::cloudstate_core::MessageError> {
+                match type_url {
+                    #(#decode_items)*
+                    _ => Err(::cloudstate_core::MessageError::unknown_type(type_url)),
+                }
+            }
+
+            #decode_command
+
+            fn encode(&self) -> Result<(String, Vec<u8>), ::cloudstate_core::MessageError> {
+                match self {
+                    #(#encode_items)*
+                }
+            }
+
+            fn type_urls() -> Option<Vec<String>> {
+                Some(vec![#(#type_urls.to_owned()),*])
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use proc_macro2::TokenStream;
+use prost_build::{Config, Service};
+use std::io;
+use std::path::{Path, PathBuf};
+
+mod descriptor;
+mod entity;
+
+pub use descriptor::write_file_descriptor_set;
+
+// Generates the messages of the .proto files along with the Cloudstate entity types of their services.
+// For each service, e.g. `ShoppingCart`, it generates:
+// - `ShoppingCartCommand`, an enum with a variant per RPC method holding its input message,
+// - `ShoppingCartResponse`, an enum with a variant per RPC method holding its output message,
+// - `ShoppingCartHandler<C>`, a trait with a method per RPC method, the commands are dispatched to it,
+// and the `AnyMessage` impls of both enums.
+// The generated code needs the `cloudstate-core` and `prost` crates.
+// It also writes the descriptor set of the services, see `write_file_descriptor_set`.
+pub fn configure() -> Builder {
+    Builder {
+        build_client: false,
+        build_server: false,
+        extern_path: vec![],
+        out_dir: None,
+        format: true,
+        file_descriptor_set: true,
+    }
+}
+
+// Compiles the .proto files into `OUT_DIR`, see `configure`
+pub fn compile_protos<P: AsRef<Path>>(protos: &[P], includes: &[P]) -> io::Result<()> {
+    configure().compile(protos, includes)
+}
+
+#[derive(Debug, Clone)]
+pub struct Builder {
+    build_client: bool,
+    build_server: bool,
+    extern_path: Vec<(String, String)>,
+    out_dir: Option<PathBuf>,
+    format: bool,
+    file_descriptor_set: bool,
+}
+
+impl Builder {
+
+    // The gRPC client of the services, e.g. to call other services. Disabled by default.
+    pub fn build_client(mut self, enable: bool) -> Self {
+        self.build_client = enable;
+        self
+    }
+
+    // The gRPC server of the services. Disabled by default since the entities are served by the Cloudstate protocol.
+    pub fn build_server(mut self, enable: bool) -> Self {
+        self.build_server = enable;
+        self
+    }
+
+    // Formats the generated files with rustfmt. Enabled by default.
+    pub fn format(mut self, run: bool) -> Self {
+        self.format = run;
+        self
+    }
+
+    // Writes the descriptor set of the services to be sent to the proxy on discovery. Enabled by default.
+    pub fn file_descriptor_set(mut self, enable: bool) -> Self {
+        self.file_descriptor_set = enable;
+        self
+    }
+
+    // Defaults to `OUT_DIR`
+    pub fn out_dir(mut self, out_dir: impl AsRef<Path>) -> Self {
+        self.out_dir = Some(out_dir.as_ref().to_path_buf());
+        self
+    }
+
+    // The messages of the protobuf package or type are already generated elsewhere, e.g. in another crate.
+    // Passed to `prost_build::Config::extern_path`.
+    pub fn extern_path(mut self, proto_path: impl AsRef<str>, rust_path: impl AsRef<str>) -> Self {
+        self.extern_path.push((proto_path.as_ref().to_owned(), rust_path.as_ref().to_owned()));
+        self
+    }
+
+    pub fn compile<P: AsRef<Path>>(self, protos: &[P], includes: &[P]) -> io::Result<()> {
+        self.compile_with_config(Config::new(), protos, includes)
+    }
+
+    pub fn compile_with_config<P: AsRef<Path>>(self, mut config: Config, protos: &[P], includes: &[P]) -> io::Result<()> {
+        let out_dir = match &self.out_dir {
+            Some(out_dir) => out_dir.clone(),
+            None => PathBuf::from(std::env::var("OUT_DIR").unwrap()),
+        };
+        let format = self.format;
+        let file_descriptor_set = self.file_descriptor_set;
+
+        config.out_dir(out_dir.clone());
+        for (proto_path, rust_path) in &self.extern_path {
+            config.extern_path(proto_path, rust_path);
+        }
+        config.service_generator(Box::new(ServiceGenerator::new(self)));
+
+        config.compile_protos(protos, includes)?;
+
+        if file_descriptor_set {
+            write_file_descriptor_set(protos, includes, &out_dir)?;
+        }
+
+        if format {
+            tonic_build::fmt(out_dir.to_str().expect("Expected utf8 out_dir"));
+        }
+        Ok(())
+    }
+}
+
+// Generates the entity types and, if enabled, the tonic client and server of each service
+struct ServiceGenerator {
+    builder: Builder,
+    clients: TokenStream,
+    servers: TokenStream,
+}
+
+impl ServiceGenerator {
+
+    fn new(builder: Builder) -> ServiceGenerator {
+        ServiceGenerator {
+            builder,
+            clients: TokenStream::new(),
+            servers: TokenStream::new(),
+        }
+    }
+}
+
+impl prost_build::ServiceGenerator for ServiceGenerator {
+
+    fn generate(&mut self, service: Service, buf: &mut String) {
+        buf.push_str(&entity::generate(&service).to_string());
+
+        // The tonic code lives in its own modules, so the messages are one level up
+        if self.builder.build_server {
+            self.servers.extend(tonic_build::server::generate(&service, "super"));
+        }
+        if self.builder.build_client {
+            self.clients.extend(tonic_build::client::generate(&service, "super"));
+        }
+    }
+
+    fn finalize(&mut self, buf: &mut String) {
+        if !self.clients.is_empty() {
+            buf.push_str(&std::mem::take(&mut self.clients).to_string());
+        }
+        if !self.servers.is_empty() {
+            buf.push_str(&std::mem::take(&mut self.servers).to_string());
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use proc_macro2::{Ident, TokenStream};
+use quote::quote;
+
+// The code generated for the entity types, shared by the `AnyMessage` derive and cloudstate-build.
+// The generated code needs the `cloudstate-core` and `prost` crates.
+
+// Sent for the replies of `()`, prost maps `google.protobuf.Empty` to it
+pub const EMPTY_TYPE_URL: &str = "type.googleapis.com/google.protobuf.Empty";
+
+// A command along with its reply, e.g. `AddItem(AddLineItem) -> google.protobuf.Empty`
+pub struct CommandReply {
+    // The variant of both the command and the reply enums
+    pub variant: Ident,
+    // The method of the handler trait
+    pub method: Ident,
+    pub command: syn::Type,
+    pub reply: syn::Type,
+    pub type_url: String,
+}
+
+// There's a handler trait with a method per command returning a response of its own reply type,
+// e.g. `Response::NoReply` when the command is forwarded, and the command is dispatched to it. The replies are wrapped in an enum.
+pub struct Replies<'a> {
+    pub vis: TokenStream,
+    // The attributes of the reply enum, e.g. `#[derive(Debug)]`
+    pub attrs: TokenStream,
+    pub command_name: &'a Ident,
+    pub reply_name: &'a Ident,
+    pub handler_name: &'a Ident,
+    pub commands: Vec<CommandReply>,
+}
+
+impl<'a> Replies<'a> {
+
+    pub fn generate(&self) -> TokenStream {
+        let Replies { vis, attrs, command_name, reply_name, handler_name, .. } = self;
+
+        let mut reply_variants = vec![];
+        let mut handler_methods = vec![];
+        let mut dispatch_items = vec![];
+        let mut decode_items = vec![];
+        let mut encode_items = vec![];
+        let mut type_urls: Vec<&String> = vec![];
+
+        for CommandReply { variant, method, command, reply, type_url } in &self.commands {
+            reply_variants.push(quote!(#variant(#reply)));
+            handler_methods.push(quote!(
+                fn #method(&self, command: #command, context: &mut C) -> Result<::cloudstate_core::Response<#reply>, ::cloudstate_core::CommandError>;
+            ));
+            dispatch_items.push(quote!(
+                #command_name::#variant(command) => <H as #handler_name<C>>::#method(handler, command, context)
+                    .map(|response| response.map(#reply_name::#variant)),
+            ));
+            // The reply type alone doesn't tell which command it's for, so the first one wins
+            if !type_urls.contains(&type_url) {
+                let decode = decode_message(reply_name, variant, type_url, reply);
+                decode_items.push(quote!(#type_url => #decode,));
+                type_urls.push(type_url);
+            }
+            encode_items.push(if type_url == EMPTY_TYPE_URL {
+                quote!(#reply_name::#variant(()) => Ok((#type_url.to_owned(), vec![])),)
+            } else {
+                let encode = encode_message(type_url);
+                quote!(#reply_name::#variant(msg) => #encode,)
+            });
+        }
+
+        quote! {
+            #attrs
+            #vis enum #reply_name {
+                #(#reply_variants),*
+            }
+
+            #vis trait #handler_name<C> {
+                #(#handler_methods)*
+            }
+
+            impl #command_name {
+                // The handler method returns the reply declared for the command
+                #vis fn dispatch<C, H: #handler_name<C>>(self, handler: &H, context: &mut C) -> Result<::cloudstate_core::Response<#reply_name>, ::cloudstate_core::CommandError> {
+                    match self {
+                        #(#dispatch_items)*
+                    }
+                }
+            }
+
+            impl ::cloudstate_core::AnyMessage for #reply_name {
+                #[allow(unused_variables)]
+                fn decode(type_url: &str, bytes: impl ::cloudstate_core::bytes::Buf) -> Result<Self, ::cloudstate_core::MessageError> {
+                    match type_url {
+                        #(#decode_items)*
+                        _ => Err(::cloudstate_core::MessageError::unknown_type(type_url)),
+                    }
+                }
+
+                fn encode(&self) -> Result<(String, Vec<u8>), ::cloudstate_core::MessageError> {
+                    match self {
+                        #(#encode_items)*
+                    }
+                }
+
+                fn type_urls() -> Option<Vec<String>> {
+                    Some(vec![#(#type_urls.to_owned()),*])
+                }
+            }
+        }
+    }
+}
+
+// Decodes `bytes` of `type_url` into the variant of the enum, `()` for `google.protobuf.Empty`
+pub fn decode_message(enum_name: &Ident, variant: &Ident, type_url: &str, message: &syn::Type) -> TokenStream {
+    if type_url == EMPTY_TYPE_URL {
+        quote!(Ok(#enum_name::#variant(())))
+    } else {
+        quote!(
+            <#message as ::prost::Message>::decode(bytes)
+                .map(#enum_name::#variant)
+                .map_err(|err| ::cloudstate_core::MessageError::decode(type_url, err))
+        )
+    }
+}
+
+// Encodes `msg` as a message of that type
+pub fn encode_message(type_url: &str) -> TokenStream {
+    quote!({
+        let mut buf = Vec::with_capacity(::prost::Message::encoded_len(msg));
+        match ::prost::Message::encode(msg, &mut buf) {
+            Ok(()) => Ok((#type_url.to_owned(), buf)),
+            Err(err) => Err(::cloudstate_core::MessageError::encode(#type_url, err)),
+        }
+    })
+}
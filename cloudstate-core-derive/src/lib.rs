@@ -4,13 +4,20 @@ use quote::quote;
 use syn::{self, parse_macro_input, Fields, FieldsUnnamed, Field, Type, Result, Token, LitStr};
 use syn::parse::{Parse, ParseStream};
 use syn::spanned::Spanned;
+use cloudstate_codegen::{CommandReply, Replies, EMPTY_TYPE_URL, encode_message};
 
-#[proc_macro_derive(AnyMessage, attributes(package))]
+#[proc_macro_derive(AnyMessage, attributes(package, command, reply, no_from))]
 pub fn cloudstate_prost_derive(input: TokenStream) -> TokenStream {
-    let ast = syn::parse(input).unwrap();
-    impl_command_macro(&ast)
+    let ast = parse_macro_input!(input as syn::DeriveInput);
+    match impl_command_macro(&ast) {
+        Ok(gen) => gen.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
 }
 
+// The type of a variant's message
+type FieldPath = syn::punctuated::Punctuated<syn::PathSegment, Token![::]>;
+
 struct ProtobufPacket(String);
 
 impl Parse for ProtobufPacket {
@@ -21,141 +28,388 @@ impl Parse for ProtobufPacket {
     }
 }
 
-fn impl_command_macro(ast: &syn::DeriveInput) -> TokenStream {
+// The reply of a command, e.g. `#[reply(Cart)]` or `#[reply(Cart, package = "com.example.shared")]`.
+// The package defaults to the one of the command enum.
+struct ReplyAttr {
+    reply: Type,
+    package: Option<String>,
+}
+
+impl Parse for ReplyAttr {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let reply: Type = input.parse()?;
+        if input.is_empty() {
+            return Ok(ReplyAttr { reply, package: None });
+        }
+        let _: Token![,] = input.parse()?;
+        let name: syn::Ident = input.parse()?;
+        if name != "package" {
+            return Err(syn::Error::new(name.span(), "Expected package = \"com.example.package\""));
+        }
+        let ProtobufPacket(package) = input.parse()?;
+        Ok(ReplyAttr { reply, package: Some(package) })
+    }
+}
+
+// Parses `#[name = "value"]`, the error points at the whole attribute
+fn parse_name_value(attr: &syn::Attribute, name: &str, example: &str) -> Result<String> {
+    syn::parse2::<ProtobufPacket>(attr.tokens.clone())
+        .map(|value| value.0)
+        .map_err(|_| syn::Error::new_spanned(attr, format!("Expected #[{} = \"{}\"]", name, example)))
+}
+
+// The package attribute may be anywhere among the other attributes of the enum
+fn package(ast: &syn::DeriveInput) -> Result<String> {
+    let mut package_attrs = ast.attrs.iter().filter(|a| a.path.is_ident("package"));
+    let package_attr = package_attrs.next().ok_or_else(|| {
+        syn::Error::new(ast.ident.span(), format!("Missing #[package = \"...\"] attribute on {}", ast.ident))
+    })?;
+    if let Some(duplicate) = package_attrs.next() {
+        return Err(syn::Error::new_spanned(duplicate, "Duplicate package attribute"));
+    }
+    parse_name_value(package_attr, "package", "com.example.package")
+}
+
+// A variant attribute may only be given once
+fn variant_attr<'a>(variant: &'a syn::Variant, name: &str) -> Result<Option<&'a syn::Attribute>> {
+    let mut attrs = variant.attrs.iter().filter(|a| a.path.is_ident(name));
+    let attr = attrs.next();
+    if let Some(duplicate) = attrs.next() {
+        return Err(syn::Error::new_spanned(duplicate, format!("Duplicate {} attribute", name)));
+    }
+    Ok(attr)
+}
+
+// The variant or the newtype struct holds a message, e.g. `AddLine(AddLineItem)`
+fn field_message<'a>(fields: &'a Fields, name: &syn::Ident, item: &dyn quote::ToTokens) -> Result<&'a FieldPath> {
+    let unnamed = match fields {
+        Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => unnamed,
+        _ => return Err(syn::Error::new_spanned(item, format!(
+            "Expected a single unnamed field holding the message, e.g. {}(Message)", name))),
+    };
+    let fields: Vec<&Field> = unnamed.iter().collect();
+    if fields.len() != 1 {
+        return Err(syn::Error::new_spanned(unnamed, format!(
+            "Expected a single unnamed field holding the message in {}, found {}", name, fields.len())));
+    }
+    match &fields[0].ty {
+        Type::Path(type_path) if type_path.qself.is_none() && !type_path.path.segments.is_empty() =>
+            Ok(&type_path.path.segments),
+        ty => Err(syn::Error::new_spanned(ty, "The field has to be a message type")),
+    }
+}
+
+fn impl_command_macro(ast: &syn::DeriveInput) -> Result<proc_macro2::TokenStream> {
     let type_name = &ast.ident;
 
-    let attrs = &ast.attrs;
-
-    let package_attr_opt = attrs.iter().find(|a| {
-        a.path.segments.iter().find(|p| {
-            p.ident.to_string() == "package"
-        }).is_some()
-    });
-
-    let protobuf_packet =
-        if let Some(package_attr) = package_attr_opt {
-            let tks = proc_macro::TokenStream::from(package_attr.tokens.clone());
-            // TODO pass package_attr.span() to point to in the error message
-            //  instead of parse_macro_input!(tks as ProtobufPacket) that will point to the derive macro
-            match parse_macro_input::parse::<ProtobufPacket>(tks)
-                .map_err(|e| syn::Error::new(package_attr.span(), e.to_string())) {
-                Ok(data) => data,
-                Err(err) => {
-                    return TokenStream::from(err.to_compile_error());
-                }
-            }
-        } else {
-            panic!("Not found package attribute!")
-        };
+    let protobuf_packet = ProtobufPacket(package(ast)?);
 
-    let variants: Vec<(_, _)> = match &ast.data {
-        syn::Data::Enum(data_enum) => {
-            data_enum.variants.iter().map(|v| {
-                let field_path = match v.fields {
-                    Fields::Unnamed(FieldsUnnamed{ ref unnamed, .. }) => {
-                        let fs: Vec<&Field> = unnamed.iter().collect();
-                        if fs.len() == 1 {
-                            match &fs[0].ty {
-                                Type::Path(type_path) => {
-                                    let result = &type_path.path.segments;
-                                    if result.is_empty() {
-                                        panic!("An empty path type provided: {}!", v.ident)
-                                    }
-                                    result
-                                },
-                                _ => {
-                                    panic!("Only single non-generic struct parameter is allowed for enum variant {}!", v.ident) //TODO properly handle it
-                                },
-                            }
-                            //==> type = Path(TypePath { qself: None, path: Path { leading_colon: None, segments: [PathSegment { ident: Ident { ident: "GetShoppingCart", span: #0 bytes(1423..1438) }, arguments: None }] } })
-                        }
-                        else {
-                            panic!("Exactly one unnamed paramater supported only!") //TODO properly handle it
-                        }
-                    },
-                    _ => {
-                        panic!("Only unnamed fields are supported!") //TODO properly handle it
-                    }
-                };
-                (&v.ident, field_path)
-            }).collect()
+    let data_enum = match &ast.data {
+        syn::Data::Enum(data_enum) => data_enum,
+        syn::Data::Struct(data) => {
+            let field_path = field_message(&data.fields, type_name, type_name)?;
+            return Ok(impl_newtype(type_name, &protobuf_packet.0, field_path));
         },
-        _ => vec![], //TODO return an error that only enums are supported
+        syn::Data::Union(data) => return Err(syn::Error::new(data.union_token.span,
+            "AnyMessage can only be derived for enums and newtype structs")),
     };
 
-    //TODO split parsing from code-generation
+    let variants: Vec<(_, _, _, _)> = data_enum.variants.iter().map(|v| {
+        let field_path = field_message(&v.fields, &v.ident, v)?;
+        // Binds the variant to the RPC method of that name
+        let command_name = match variant_attr(v, "command")? {
+            Some(command_attr) => Some(parse_name_value(command_attr, "command", "MethodName")?),
+            None => None,
+        };
+        // The type of the reply to the command
+        let reply = match variant_attr(v, "reply")? {
+            Some(reply_attr) => Some(reply_attr.parse_args::<ReplyAttr>()?),
+            None => None,
+        };
+        Ok((&v.ident, field_path, command_name, reply))
+    }).collect::<Result<_>>()?;
+
 
     let unknown_command = quote! {
-        unknown_command_type => {
-            eprintln!("Unknown command type: {}", unknown_command_type);
-            None
-        },
+        _ => Err(::cloudstate_core::MessageError::unknown_type(type_url)),
     };
 
-    let items: Vec<_> = variants.iter().map(|(enum_id, field_path)| {
-        let variant_name = enum_id.to_string();
+    let full_type = |field_path: &FieldPath| {
         let field_id = &field_path.last().unwrap().ident;
-        let full_type = format!("type.googleapis.com/{}.{}", protobuf_packet.0, &field_id.to_string());
+        format!("type.googleapis.com/{}.{}", protobuf_packet.0, &field_id.to_string())
+    };
+
+    let decode_variant = |enum_id: &syn::Ident, field_path: &FieldPath| {
+        let variant_name = enum_id.to_string();
         quote!(
-            #full_type => {
-                match <#field_path as Message>::decode(bytes) {
-                    Ok(cmd) => {
-                        println!("Received {:?}", cmd);
-                        Some(#type_name::#enum_id(cmd))
-                    },
-                    Err(err) => {
-                        eprintln!("Error decoding {} command: {}", #variant_name, err);
-                        None
-                    },
-                }
-            },
+            match <#field_path as Message>::decode(bytes) {
+                Ok(cmd) => {
+                    // The payload isn't logged as it may contain sensitive data
+                    ::cloudstate_core::tracing::trace!(variant = #variant_name, "Decoded message");
+                    Ok(#type_name::#enum_id(cmd))
+                },
+                Err(err) => Err(::cloudstate_core::MessageError::decode(type_url, err)),
+            }
+        )
+    };
+
+    // Several variants may hold the same message when they are bound to different commands.
+    // Only the type URL is known without the command, so it's decoded as the first variant, preferably an unbound one.
+    let mut type_url_variants = vec![];
+    for (enum_id, field_path, _, _) in variants.iter().filter(|v| v.2.is_none()).chain(variants.iter().filter(|v| v.2.is_some())) {
+        let full_type = full_type(field_path);
+        if !type_url_variants.iter().any(|(type_url, _, _)| type_url == &full_type) {
+            type_url_variants.push((full_type, enum_id, field_path));
+        }
+    }
+
+    let items: Vec<_> = type_url_variants.iter().map(|(full_type, enum_id, field_path)| {
+        let decode = decode_variant(enum_id, field_path);
+        quote!(
+            #full_type => #decode,
         )
     }).collect();
 
-    let encode_items: Vec<_> = variants.iter().map(|(enum_id, field_path)| {
-        let _variant_name = enum_id.to_string();
-        let field_id = &field_path.last().unwrap().ident;
-        let full_type = format!("type.googleapis.com/{}.{}", protobuf_packet.0, &field_id.to_string());
+    let command_items: Vec<_> = variants.iter().filter_map(|(enum_id, field_path, command_name, _)| {
+        command_name.as_ref().map(|command_name| {
+            let full_type = full_type(field_path);
+            let decode = decode_variant(enum_id, field_path);
+            quote!(
+                (#command_name, #full_type) => #decode,
+            )
+        })
+    }).collect();
+
+    // The commands that aren't bound to a variant holding their message are decoded by the type URL,
+    // unless all the variants holding that message are bound to other commands.
+    let unbound_type_urls: Vec<_> = variants.iter()
+        .filter(|(_, _, command_name, _)| command_name.is_none())
+        .map(|(_, field_path, _, _)| full_type(field_path))
+        .collect();
+    let mut bound_only_type_urls: Vec<_> = variants.iter()
+        .map(|(_, field_path, _, _)| full_type(field_path))
+        .filter(|full_type| !unbound_type_urls.contains(full_type))
+        .collect();
+    bound_only_type_urls.sort();
+    bound_only_type_urls.dedup();
+
+    let unknown_command_items = if bound_only_type_urls.is_empty() {
+        quote!()
+    } else {
         quote!(
-            #type_name::#enum_id(msg) => {
-                let mut buf = vec![];
-                ::prost::Message::encode(msg, &mut buf).unwrap(); //TODO handle possible encode error properly
-                Some((#full_type.to_owned(), buf))
-            },
+            #((_, #bound_only_type_urls))|* => Err(::cloudstate_core::MessageError::unknown_command(command_name, type_url)),
+        )
+    };
+
+    let encode_items: Vec<_> = variants.iter().map(|(enum_id, field_path, _, _)| {
+        let full_type = full_type(field_path);
+        let encode = encode_message(&full_type);
+        quote!(
+            #type_name::#enum_id(ref msg) => #encode,
         )
     }).collect();
 
+    let type_urls: Vec<_> = variants.iter().map(|(_, field_path, _, _)| full_type(field_path)).collect();
+
+    let from_impls = impl_from(type_name, data_enum, &variants)?;
+
     let gen = quote! {
         impl AnyMessage for #type_name {
-            fn decode(type_url: &str, bytes: Bytes) -> Option<Self> {
+            fn decode(type_url: &str, bytes: impl ::cloudstate_core::bytes::Buf) -> Result<Self, ::cloudstate_core::MessageError> {
                 match type_url {
                     #(#items)*
                     #unknown_command
                 }
             }
 
-            fn encode(&self) -> Option<(String, Vec<u8>)> {
-                match self {
+            fn decode_command(command_name: &str, type_url: &str, bytes: impl ::cloudstate_core::bytes::Buf) -> Result<Self, ::cloudstate_core::MessageError> {
+                match (command_name, type_url) {
+                    #(#command_items)*
+                    #unknown_command_items
+                    _ => <Self as AnyMessage>::decode(type_url, bytes),
+                }
+            }
+
+            fn encode(&self) -> Result<(String, Vec<u8>), ::cloudstate_core::MessageError> {
+                match *self {
                     #(#encode_items)*
-                    _ => None,
                 }
             }
+
+            fn type_urls() -> Option<Vec<String>> {
+                Some(vec![#(#type_urls.to_owned()),*])
+            }
         }
     };
 
+    let replies = impl_replies(ast, &protobuf_packet.0, &variants)?;
+
+    Ok(quote! {
+        #gen
+        #from_impls
+        #replies
+    })
+}
+
+// Lets a variant be built from its message, e.g. `context.emit_event(ItemAdded { .. })`.
+// When several variants hold the same message, all of them but one have to opt out with `#[no_from]`.
+fn impl_from(type_name: &syn::Ident,
+             data_enum: &syn::DataEnum,
+             variants: &[(&syn::Ident, &FieldPath, Option<String>, Option<ReplyAttr>)]) -> Result<proc_macro2::TokenStream> {
+    let mut converted: Vec<(String, &syn::Ident)> = vec![];
+    let mut from_impls = vec![];
+
+    for (variant, (enum_id, field_path, _, _)) in data_enum.variants.iter().zip(variants) {
+        if variant.attrs.iter().any(|a| a.path.is_ident("no_from")) {
+            continue;
+        }
+        let message = quote!(#field_path).to_string();
+        if let Some((_, other)) = converted.iter().find(|(m, _)| m == &message) {
+            return Err(syn::Error::new(enum_id.span(), format!(
+                "{} holds the same message as {}, mark one of them with #[no_from]", enum_id, other)));
+        }
+        from_impls.push(quote!(
+            impl From<#field_path> for #type_name {
+                fn from(msg: #field_path) -> Self {
+                    #type_name::#enum_id(msg)
+                }
+            }
+        ));
+        converted.push((message, enum_id));
+    }
+
+    Ok(quote!(#(#from_impls)*))
+}
+
+// The struct always holds the same message, e.g. a snapshot
+fn impl_newtype(type_name: &syn::Ident, package: &str, field_path: &FieldPath) -> proc_macro2::TokenStream {
+    let type_url = format!("type.googleapis.com/{}.{}", package, field_path.last().unwrap().ident);
+    let encode = encode_message(&type_url);
+
+    quote! {
+        impl AnyMessage for #type_name {
+            fn decode(type_url: &str, bytes: impl ::cloudstate_core::bytes::Buf) -> Result<Self, ::cloudstate_core::MessageError> {
+                if type_url != #type_url {
+                    return Err(::cloudstate_core::MessageError::unknown_type(type_url));
+                }
+                <#field_path as Message>::decode(bytes)
+                    .map(#type_name)
+                    .map_err(|err| ::cloudstate_core::MessageError::decode(type_url, err))
+            }
+
+            fn encode(&self) -> Result<(String, Vec<u8>), ::cloudstate_core::MessageError> {
+                let msg = &self.0;
+                #encode
+            }
+
+            fn type_urls() -> Option<Vec<String>> {
+                Some(vec![#type_url.to_owned()])
+            }
+        }
+
+        impl From<#field_path> for #type_name {
+            fn from(msg: #field_path) -> Self {
+                #type_name(msg)
+            }
+        }
+    }
+}
+
+// Names the protobuf type of a message struct, e.g. generated by prost, so it's an `AnyMessage` on its own.
+// The name is the one of the struct.
+#[proc_macro_derive(MessageName, attributes(package))]
+pub fn message_name_derive(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as syn::DeriveInput);
+    let type_name = &ast.ident;
+    let package = match package(&ast) {
+        Ok(package) => package,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    let name = type_name.to_string();
+
+    let gen = quote! {
+        impl ::cloudstate_core::MessageName for #type_name {
+            const PACKAGE: &'static str = #package;
+            const NAME: &'static str = #name;
+        }
+    };
     gen.into()
 }
 
+// When the commands declare their replies, there's a handler trait with a method per command
+// returning a response of its own reply type, e.g. `Response::NoReply` when the command is forwarded,
+// and the command is dispatched to it, see `cloudstate_codegen::Replies`.
+// The replies are wrapped in a generated enum, a `()` reply is sent as `google.protobuf.Empty`.
+fn impl_replies(ast: &syn::DeriveInput,
+                package: &str,
+                variants: &[(&syn::Ident, &FieldPath, Option<String>, Option<ReplyAttr>)]) -> Result<proc_macro2::TokenStream> {
+    if variants.iter().all(|v| v.3.is_none()) {
+        return Ok(quote!());
+    }
+
+    let type_name = &ast.ident;
+    let vis = &ast.vis;
+    let reply_name = syn::Ident::new(&format!("{}Reply", type_name), type_name.span());
+    let handler_name = syn::Ident::new(&format!("{}Handler", type_name), type_name.span());
+
+    let commands = variants.iter().map(|(enum_id, field_path, _, reply)| {
+        let ReplyAttr { reply, package: reply_package } = reply.as_ref().ok_or_else(|| {
+            syn::Error::new(enum_id.span(), format!("Missing reply attribute on {}, either every command declares its reply or none", enum_id))
+        })?;
+
+        let type_url = match reply {
+            Type::Tuple(tuple) if tuple.elems.is_empty() => EMPTY_TYPE_URL.to_owned(),
+            Type::Path(type_path) => {
+                let reply_id = &type_path.path.segments.last()
+                    .ok_or_else(|| syn::Error::new(type_path.span(), "An empty path type provided"))?
+                    .ident;
+                format!("type.googleapis.com/{}.{}", reply_package.as_deref().unwrap_or(package), reply_id)
+            },
+            _ => return Err(syn::Error::new(reply.span(), "The reply has to be a message type or ()")),
+        };
+
+        Ok(CommandReply {
+            variant: (*enum_id).clone(),
+            method: syn::Ident::new(&snake_case(&enum_id.to_string()), enum_id.span()),
+            command: syn::parse_quote!(#field_path),
+            reply: reply.clone(),
+            type_url,
+        })
+    }).collect::<Result<_>>()?;
+
+    let replies = Replies {
+        vis: quote!(#vis),
+        attrs: quote!(),
+        command_name: type_name,
+        reply_name: &reply_name,
+        handler_name: &handler_name,
+        commands,
+    };
+    Ok(replies.generate())
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
 #[test]
 fn foo() {
     let type_name = "type.googleapis.com/com.example.shoppingcart.AddLineItem".to_owned();
 
     let match_with = ".".to_string() + "AddLineItem";
 
-    let result = match type_name {
-        s if s.ends_with(&match_with) => true,
-        _ => false,
-    };
+    let result = matches!(type_name, s if s.ends_with(&match_with));
 
     assert!(result);
 }
@@ -164,10 +418,7 @@ fn foo() {
 fn bar() {
     let type_name = "yes".to_string();
 
-    let result = match type_name.as_ref() {
-        "yes" => true,
-        _ => false,
-    };
+    let result = matches!(type_name.as_ref(), "yes");
 
     assert!(result);
 }
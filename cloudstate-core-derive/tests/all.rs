@@ -6,4 +6,16 @@ fn tests() {
     t.compile_fail("tests/missing_package_attr.rs");
     t.compile_fail("tests/incorrect_package_attribute.rs");
     t.compile_fail("tests/package_attribute_without_value.rs");
-}
\ No newline at end of file
+    t.compile_fail("tests/duplicate_package_attribute.rs");
+    t.compile_fail("tests/named_struct_fields.rs");
+    t.compile_fail("tests/named_variant_fields.rs");
+    t.compile_fail("tests/several_variant_fields.rs");
+    t.compile_fail("tests/non_message_variant_field.rs");
+    t.compile_fail("tests/union.rs");
+    t.compile_fail("tests/incorrect_command_attribute.rs");
+    t.compile_fail("tests/duplicate_command_attribute.rs");
+    t.compile_fail("tests/missing_reply_attribute.rs");
+    t.compile_fail("tests/incorrect_reply_attribute.rs");
+    t.compile_fail("tests/duplicate_reply_attribute.rs");
+    t.compile_fail("tests/shared_variant_message.rs");
+}
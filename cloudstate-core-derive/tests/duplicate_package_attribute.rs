@@ -0,0 +1,14 @@
+use cloudstate_core_derive::AnyMessage;
+
+mod shopping_cart;
+use shopping_cart::*;
+
+#[derive(AnyMessage)]
+#[package = "com.example.shoppingcart"]
+#[package = "com.example.other"]
+pub enum ShoppingCartCommand {
+    AddLine(AddLineItem),
+    GetCart(GetShoppingCart),
+}
+
+fn main() {}
@@ -0,0 +1,16 @@
+use cloudstate_core_derive::AnyMessage;
+
+mod shopping_cart;
+use shopping_cart::*;
+
+#[derive(AnyMessage)]
+#[package = "com.example.shoppingcart"]
+pub enum ShoppingCartCommand {
+    #[reply(())]
+    AddLine(AddLineItem),
+    #[reply(Cart)]
+    #[reply(())]
+    GetCart(GetShoppingCart),
+}
+
+fn main() {}
@@ -3,8 +3,8 @@ use cloudstate_core_derive::AnyMessage;
 mod shopping_cart;
 use shopping_cart::*;
 
-#[package("com.example.shoppingcart")]
 #[derive(AnyMessage)]
+#[package("com.example.shoppingcart")]
 pub enum ShoppingCartCommand {
     AddLine(AddLineItem),
     RemoveLine(RemoveLineItem),
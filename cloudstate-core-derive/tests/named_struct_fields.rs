@@ -0,0 +1,12 @@
+use cloudstate_core_derive::AnyMessage;
+
+mod shopping_cart;
+use shopping_cart::*;
+
+#[derive(AnyMessage)]
+#[package = "com.example.shoppingcart"]
+pub struct ShoppingCartCommand {
+    add_line: AddLineItem,
+}
+
+fn main() {}
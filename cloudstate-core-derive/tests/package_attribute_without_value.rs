@@ -3,8 +3,8 @@ use cloudstate_core_derive::AnyMessage;
 mod shopping_cart;
 use shopping_cart::*;
 
-#[package]
 #[derive(AnyMessage)]
+#[package]
 pub enum ShoppingCartCommand {
     AddLine(AddLineItem),
     RemoveLine(RemoveLineItem),
@@ -0,0 +1,13 @@
+use cloudstate_core_derive::AnyMessage;
+
+mod shopping_cart;
+use shopping_cart::*;
+
+#[derive(AnyMessage)]
+#[package = "com.example.shoppingcart"]
+pub enum ShoppingCartCommand {
+    AddLine(AddLineItem, RemoveLineItem),
+    GetCart(GetShoppingCart),
+}
+
+fn main() {}
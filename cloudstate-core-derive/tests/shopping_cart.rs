@@ -52,7 +52,8 @@ pub struct LineItem {
     pub quantity: i32,
 }
 
-#[derive(Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message, cloudstate_core_derive::MessageName)]
+#[package = "com.example.shoppingcart"]
 pub struct Cart {
     #[prost(message, repeated, tag = "1")]
     pub items: ::std::vec::Vec<LineItem>,
@@ -1,6 +1,6 @@
 use ::prost::Message;
 use bytes::Bytes;
-use cloudstate_core::AnyMessage;
+use cloudstate_core::{AnyMessage, MessageError, Response};
 use cloudstate_core_derive::AnyMessage;
 
 mod shopping_cart;
@@ -8,8 +8,8 @@ use shopping_cart::*;
 
 //TODO Fix these tests. They've been built for Prost but now the macro generates code for protobuf.
 
-#[package = "com.example.shoppingcart"]
 #[derive(AnyMessage, Debug, PartialEq)]
+#[package = "com.example.shoppingcart"]
 pub enum ShoppingCartCommand {
     AddLine(AddLineItem),
     RemoveLine(RemoveLineItem),
@@ -21,9 +21,9 @@ fn test_command_decoder() {
 
     let bytes = encode(&msg);
 
-    let result = <ShoppingCartCommand as AnyMessage>::decode("type.googleapis.com/com.example.shoppingcart.AddLineItem".to_owned(), bytes);
+    let result = <ShoppingCartCommand as AnyMessage>::decode("type.googleapis.com/com.example.shoppingcart.AddLineItem", bytes);
 
-    assert_eq!(result, Some(ShoppingCartCommand::AddLine(msg)));
+    assert_eq!(result, Ok(ShoppingCartCommand::AddLine(msg)));
 }
 
 fn test_command_decoder_with_incorrect_type() {
@@ -31,12 +31,160 @@ fn test_command_decoder_with_incorrect_type() {
 
     let bytes = encode(&msg);
 
-    let result = <ShoppingCartCommand as AnyMessage>::decode("AddLineItem".to_owned(), bytes);
+    let result = <ShoppingCartCommand as AnyMessage>::decode("AddLineItem", bytes);
+
+    assert_eq!(result, Err(MessageError::unknown_type("AddLineItem")));
+}
+
+/// The package attribute may be anywhere after the derive
+#[derive(Debug)]
+#[derive(AnyMessage)]
+#[allow(dead_code)]
+#[package = "com.example.shoppingcart"]
+#[derive(PartialEq)]
+pub enum BoundShoppingCartCommand {
+    #[command = "AddItem"]
+    AddLine(AddLineItem),
+    #[command = "AddGift"]
+    #[no_from]
+    AddGift(AddLineItem),
+    #[command = "RemoveItem"]
+    RemoveLine(RemoveLineItem),
+    GetCart(GetShoppingCart),
+}
+
+fn test_command_decoder_by_command_name() {
+    let msg = add_line_item();
+    let type_url = "type.googleapis.com/com.example.shoppingcart.AddLineItem";
+
+    let result = <BoundShoppingCartCommand as AnyMessage>::decode_command("AddGift", type_url, encode(&msg));
+    assert_eq!(result, Ok(BoundShoppingCartCommand::AddGift(msg.clone())));
+
+    let result = <BoundShoppingCartCommand as AnyMessage>::decode_command("AddItem", type_url, encode(&msg));
+    assert_eq!(result, Ok(BoundShoppingCartCommand::AddLine(msg.clone())));
+
+    // All the variants holding the message are bound to other commands
+    let result = <BoundShoppingCartCommand as AnyMessage>::decode_command("Unknown", type_url, encode(&msg));
+    assert_eq!(result, Err(MessageError::unknown_command("Unknown", type_url)));
+
+    // Unbound variants are decoded whatever the command is
+    let get_cart = GetShoppingCart { user_id: "user_id".to_owned() };
+    let result = <BoundShoppingCartCommand as AnyMessage>::decode_command("GetCart", "type.googleapis.com/com.example.shoppingcart.GetShoppingCart", encode(&get_cart));
+    assert_eq!(result, Ok(BoundShoppingCartCommand::GetCart(get_cart)));
+}
+
+#[derive(AnyMessage, Debug, PartialEq)]
+#[package = "com.example.shoppingcart"]
+pub enum RepliedShoppingCartCommand {
+    #[reply(())]
+    AddLine(AddLineItem),
+    #[reply(Cart)]
+    GetCart(GetShoppingCart),
+    // The reply is in another package than the commands
+    #[reply(Cart, package = "com.example.shared")]
+    GetSharedCart(RemoveLineItem),
+}
+
+struct CartHandler;
+
+// Records what the handler did with the commands
+#[derive(Default)]
+struct CartContext {
+    added_lines: u32,
+    forwarded_to: Option<&'static str>,
+}
+
+impl RepliedShoppingCartCommandHandler<CartContext> for CartHandler {
+    fn add_line(&self, _command: AddLineItem, context: &mut CartContext) -> Result<Response<()>, cloudstate_core::CommandError> {
+        context.added_lines += 1;
+        Ok(Response::Reply(()))
+    }
+
+    fn get_cart(&self, _command: GetShoppingCart, _context: &mut CartContext) -> Result<Response<Cart>, cloudstate_core::CommandError> {
+        Ok(Response::Reply(Cart { items: vec![] }))
+    }
+
+    // Forwarded to the shared cart, which replies instead
+    fn get_shared_cart(&self, _command: RemoveLineItem, context: &mut CartContext) -> Result<Response<Cart>, cloudstate_core::CommandError> {
+        context.forwarded_to = Some("com.example.shared.SharedCart");
+        Ok(Response::NoReply)
+    }
+}
+
+fn test_command_dispatch_to_typed_replies() {
+    let mut context = CartContext::default();
+
+    let reply = match RepliedShoppingCartCommand::AddLine(add_line_item()).dispatch(&CartHandler, &mut context) {
+        Ok(Response::Reply(reply)) => reply,
+        _ => panic!("Expected a reply"),
+    };
+    assert_eq!(context.added_lines, 1);
+    assert_eq!(reply.encode(), Ok(("type.googleapis.com/google.protobuf.Empty".to_owned(), vec![])));
+
+    let command = RepliedShoppingCartCommand::GetCart(GetShoppingCart { user_id: "user_id".to_owned() });
+    let reply = match command.dispatch(&CartHandler, &mut context) {
+        Ok(Response::Reply(reply)) => reply,
+        _ => panic!("Expected a reply"),
+    };
+    assert_eq!(reply.encode().map(|(type_url, _)| type_url), Ok("type.googleapis.com/com.example.shoppingcart.Cart".to_owned()));
+
+    let command = RepliedShoppingCartCommand::GetSharedCart(RemoveLineItem { user_id: "user_id".to_owned(), product_id: "product_id".to_owned() });
+    match command.dispatch(&CartHandler, &mut context) {
+        Ok(Response::NoReply) => (),
+        _ => panic!("Expected no reply for a forwarded command"),
+    }
+    assert_eq!(context.forwarded_to, Some("com.example.shared.SharedCart"));
+}
+
+#[derive(AnyMessage, Debug, PartialEq)]
+#[package = "com.example.shoppingcart"]
+pub struct CartSnapshot(Cart);
+
+fn test_from_message() {
+    let msg = add_line_item();
+
+    assert_eq!(ShoppingCartCommand::from(msg.clone()), ShoppingCartCommand::AddLine(msg.clone()));
+    // The other variant holding the message opted out
+    assert_eq!(BoundShoppingCartCommand::from(msg.clone()), BoundShoppingCartCommand::AddLine(msg));
+
+    let cart = Cart { items: vec![] };
+    assert_eq!(CartSnapshot::from(cart.clone()), CartSnapshot(cart));
+}
+
+fn test_newtype_decoder() {
+    let cart = Cart { items: vec![LineItem { product_id: "product_id".to_owned(), name: "name".to_owned(), quantity: 1 }] };
+    let type_url = "type.googleapis.com/com.example.shoppingcart.Cart";
+
+    let result = <CartSnapshot as AnyMessage>::decode(type_url, encode(&cart));
+    assert_eq!(result, Ok(CartSnapshot(cart.clone())));
+
+    let result = <CartSnapshot as AnyMessage>::decode("type.googleapis.com/com.example.shoppingcart.LineItem", encode(&cart));
+    assert_eq!(result, Err(MessageError::unknown_type("type.googleapis.com/com.example.shoppingcart.LineItem")));
+
+    // Not a cart at all
+    let result = <CartSnapshot as AnyMessage>::decode(type_url, Bytes::from_static(&[0xff]));
+    assert!(matches!(result, Err(MessageError::Decode { .. })));
+
+    assert_eq!(CartSnapshot(cart.clone()).encode(), Ok((type_url.to_owned(), encode(&cart).to_vec())));
+    assert_eq!(<CartSnapshot as AnyMessage>::type_urls(), Some(vec![type_url.to_owned()]));
+}
+
+fn test_named_message() {
+    let cart = Cart { items: vec![] };
+    let type_url = "type.googleapis.com/com.example.shoppingcart.Cart";
 
-    assert_eq!(result, None);
+    // The message is used as is thanks to its name
+    let result = <Cart as AnyMessage>::decode(type_url, encode(&cart));
+    assert_eq!(result, Ok(cart.clone()));
+    assert_eq!(AnyMessage::encode(&cart), Ok((type_url.to_owned(), vec![])));
 }
 
 fn main() {
     test_command_decoder();
     test_command_decoder_with_incorrect_type();
+    test_command_decoder_by_command_name();
+    test_command_dispatch_to_typed_replies();
+    test_from_message();
+    test_newtype_decoder();
+    test_named_message();
 }
\ No newline at end of file
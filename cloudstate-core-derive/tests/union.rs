@@ -0,0 +1,10 @@
+use cloudstate_core_derive::AnyMessage;
+
+#[derive(AnyMessage)]
+#[package = "com.example.shoppingcart"]
+pub union ShoppingCartCommand {
+    item_count: u32,
+    quantity: i32,
+}
+
+fn main() {}
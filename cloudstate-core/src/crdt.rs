@@ -0,0 +1,990 @@
+use bytes::Bytes;
+use std::collections::{BTreeMap, BTreeSet};
+use std::marker::PhantomData;
+use tracing::{debug, warn};
+use crate::{AnyMessage, EntityAction, MessageTypes, Response};
+
+pub use crate::CommandError;
+
+// The CRDT state and deltas as they are sent over the wire.
+// The proxy is responsible for merging, the user function only holds the current value
+// and sends deltas for the changes it makes. Set elements and register values are
+// `google.protobuf.Any` encoded messages represented as a (type_url, bytes) pair.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CrdtState {
+    GCounter {
+        value: u64,
+    },
+    PNCounter {
+        value: i64,
+    },
+    GSet {
+        items: Vec<(String, Bytes)>,
+    },
+    ORSet {
+        items: Vec<(String, Bytes)>,
+    },
+    LWWRegister {
+        value: Option<(String, Bytes)>,
+    },
+    Flag {
+        value: bool,
+    },
+    Vote {
+        votes_for: u32,
+        total_voters: u32,
+        self_vote: bool,
+    },
+    //TODO ORMap
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CrdtDelta {
+    GCounter {
+        increment: u64,
+    },
+    PNCounter {
+        change: i64,
+    },
+    GSet {
+        added: Vec<(String, Bytes)>,
+    },
+    ORSet {
+        cleared: bool,
+        removed: Vec<(String, Bytes)>,
+        added: Vec<(String, Bytes)>,
+    },
+    LWWRegister {
+        value: Option<(String, Bytes)>,
+    },
+    Flag {
+        value: bool,
+    },
+    Vote {
+        // Only set by the user function
+        self_vote: bool,
+        // Only set by the proxy
+        votes_for: u32,
+        total_voters: u32,
+    },
+}
+
+impl CrdtState {
+    fn name(&self) -> &'static str {
+        match self {
+            CrdtState::GCounter { .. } => "GCounter",
+            CrdtState::PNCounter { .. } => "PNCounter",
+            CrdtState::GSet { .. } => "GSet",
+            CrdtState::ORSet { .. } => "ORSet",
+            CrdtState::LWWRegister { .. } => "LWWRegister",
+            CrdtState::Flag { .. } => "Flag",
+            CrdtState::Vote { .. } => "Vote",
+        }
+    }
+}
+
+impl CrdtDelta {
+    fn name(&self) -> &'static str {
+        match self {
+            CrdtDelta::GCounter { .. } => "GCounter",
+            CrdtDelta::PNCounter { .. } => "PNCounter",
+            CrdtDelta::GSet { .. } => "GSet",
+            CrdtDelta::ORSet { .. } => "ORSet",
+            CrdtDelta::LWWRegister { .. } => "LWWRegister",
+            CrdtDelta::Flag { .. } => "Flag",
+            CrdtDelta::Vote { .. } => "Vote",
+        }
+    }
+}
+
+// A CRDT held in memory by the user function.
+// It's updated either by the state and deltas received from the proxy or by the entity's command handler.
+// The changes made by the command handler are accumulated and taken as a delta to be sent back to the proxy.
+pub trait Crdt: Default + Clone {
+
+    fn apply_state(&mut self, state: CrdtState) -> Result<(), String>;
+
+    fn apply_delta(&mut self, delta: CrdtDelta) -> Result<(), String>;
+
+    fn state(&self) -> CrdtState;
+
+    // Returns the changes made since the last call, if any
+    fn take_delta(&mut self) -> Option<CrdtDelta>;
+}
+
+fn unexpected(expected: &str, actual: &str) -> String {
+    format!("Expected {} but received {}", expected, actual)
+}
+
+fn encode_item<T: AnyMessage>(item: &T) -> Option<(String, Bytes)> {
+    match item.encode() {
+        Ok((type_url, bytes)) => Some((type_url, Bytes::from(bytes))),
+        Err(err) => {
+            warn!(error = %err, "Couldn't encode CRDT item");
+            None
+        },
+    }
+}
+
+// The items which can't be decoded are skipped
+fn decode_items<'a, T: AnyMessage + 'a>(items: impl Iterator<Item = &'a (String, Bytes)> + 'a) -> impl Iterator<Item = T> + 'a {
+    items.filter_map(|(type_url, bytes)| match <T as AnyMessage>::decode(type_url, &bytes[..]) {
+        Ok(item) => Some(item),
+        Err(err) => {
+            warn!(error = %err, "Couldn't decode CRDT item");
+            None
+        },
+    })
+}
+
+// A Grow-only Counter
+#[derive(Clone, Default, Debug)]
+pub struct GCounter {
+    value: u64,
+    delta: u64,
+}
+
+impl GCounter {
+
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    // Saturates rather than overflowing
+    pub fn increment(&mut self, by: u64) -> u64 {
+        self.value = self.value.saturating_add(by);
+        self.delta = self.delta.saturating_add(by);
+        self.value
+    }
+}
+
+impl Crdt for GCounter {
+
+    fn apply_state(&mut self, state: CrdtState) -> Result<(), String> {
+        match state {
+            CrdtState::GCounter { value } => {
+                self.value = value;
+                Ok(())
+            },
+            other => Err(unexpected("GCounter", other.name())),
+        }
+    }
+
+    fn apply_delta(&mut self, delta: CrdtDelta) -> Result<(), String> {
+        match delta {
+            CrdtDelta::GCounter { increment } => {
+                self.value = self.value.saturating_add(increment);
+                Ok(())
+            },
+            other => Err(unexpected("GCounter", other.name())),
+        }
+    }
+
+    fn state(&self) -> CrdtState {
+        CrdtState::GCounter { value: self.value }
+    }
+
+    fn take_delta(&mut self) -> Option<CrdtDelta> {
+        if self.delta == 0 {
+            return None;
+        }
+        let increment = std::mem::take(&mut self.delta);
+        Some(CrdtDelta::GCounter { increment })
+    }
+}
+
+// A Positive-Negative Counter
+#[derive(Clone, Default, Debug)]
+pub struct PNCounter {
+    value: i64,
+    delta: i64,
+}
+
+impl PNCounter {
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    // Saturates rather than overflowing
+    pub fn increment(&mut self, by: i64) -> i64 {
+        self.value = self.value.saturating_add(by);
+        self.delta = self.delta.saturating_add(by);
+        self.value
+    }
+
+    pub fn decrement(&mut self, by: i64) -> i64 {
+        self.increment(by.saturating_neg())
+    }
+}
+
+impl Crdt for PNCounter {
+
+    fn apply_state(&mut self, state: CrdtState) -> Result<(), String> {
+        match state {
+            CrdtState::PNCounter { value } => {
+                self.value = value;
+                Ok(())
+            },
+            other => Err(unexpected("PNCounter", other.name())),
+        }
+    }
+
+    fn apply_delta(&mut self, delta: CrdtDelta) -> Result<(), String> {
+        match delta {
+            CrdtDelta::PNCounter { change } => {
+                self.value = self.value.saturating_add(change);
+                Ok(())
+            },
+            other => Err(unexpected("PNCounter", other.name())),
+        }
+    }
+
+    fn state(&self) -> CrdtState {
+        CrdtState::PNCounter { value: self.value }
+    }
+
+    fn take_delta(&mut self) -> Option<CrdtDelta> {
+        if self.delta == 0 {
+            return None;
+        }
+        let change = std::mem::take(&mut self.delta);
+        Some(CrdtDelta::PNCounter { change })
+    }
+}
+
+// A Grow-only Set
+// NOTE: items are compared by their encoded bytes, thus a stable encoding is required.
+#[derive(Clone, Debug)]
+pub struct GSet<T> {
+    items: BTreeSet<(String, Bytes)>,
+    added: BTreeSet<(String, Bytes)>,
+    item_type: PhantomData<T>,
+}
+
+impl<T> Default for GSet<T> {
+    fn default() -> Self {
+        GSet {
+            items: BTreeSet::new(),
+            added: BTreeSet::new(),
+            item_type: PhantomData,
+        }
+    }
+}
+
+impl<T: AnyMessage> GSet<T> {
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        encode_item(item).is_some_and(|v| self.items.contains(&v))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        decode_items(self.items.iter())
+    }
+
+    // Returns true if the item hasn't been in the set
+    pub fn add(&mut self, item: T) -> bool {
+        match encode_item(&item) {
+            Some(encoded) if !self.items.contains(&encoded) => {
+                self.added.insert(encoded.clone());
+                self.items.insert(encoded)
+            },
+            _ => false,
+        }
+    }
+}
+
+impl<T: AnyMessage + Clone> Crdt for GSet<T> {
+
+    fn apply_state(&mut self, state: CrdtState) -> Result<(), String> {
+        match state {
+            CrdtState::GSet { items } => {
+                self.items = items.into_iter().collect();
+                Ok(())
+            },
+            other => Err(unexpected("GSet", other.name())),
+        }
+    }
+
+    fn apply_delta(&mut self, delta: CrdtDelta) -> Result<(), String> {
+        match delta {
+            CrdtDelta::GSet { added } => {
+                self.items.extend(added);
+                Ok(())
+            },
+            other => Err(unexpected("GSet", other.name())),
+        }
+    }
+
+    fn state(&self) -> CrdtState {
+        CrdtState::GSet { items: self.items.iter().cloned().collect() }
+    }
+
+    fn take_delta(&mut self) -> Option<CrdtDelta> {
+        if self.added.is_empty() {
+            return None;
+        }
+        let added = std::mem::take(&mut self.added).into_iter().collect();
+        Some(CrdtDelta::GSet { added })
+    }
+}
+
+// An Observed-Removed Set
+// NOTE: items are compared by their encoded bytes, thus a stable encoding is required.
+#[derive(Clone, Debug)]
+pub struct ORSet<T> {
+    items: BTreeSet<(String, Bytes)>,
+    cleared: bool,
+    removed: BTreeSet<(String, Bytes)>,
+    added: BTreeSet<(String, Bytes)>,
+    item_type: PhantomData<T>,
+}
+
+impl<T> Default for ORSet<T> {
+    fn default() -> Self {
+        ORSet {
+            items: BTreeSet::new(),
+            cleared: false,
+            removed: BTreeSet::new(),
+            added: BTreeSet::new(),
+            item_type: PhantomData,
+        }
+    }
+}
+
+impl<T: AnyMessage> ORSet<T> {
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        encode_item(item).is_some_and(|v| self.items.contains(&v))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        decode_items(self.items.iter())
+    }
+
+    // Returns true if the item hasn't been in the set
+    pub fn add(&mut self, item: T) -> bool {
+        match encode_item(&item) {
+            Some(encoded) if !self.items.contains(&encoded) => {
+                if !self.removed.remove(&encoded) {
+                    self.added.insert(encoded.clone());
+                }
+                self.items.insert(encoded)
+            },
+            _ => false,
+        }
+    }
+
+    // Returns true if the item has been in the set
+    pub fn remove(&mut self, item: &T) -> bool {
+        match encode_item(item) {
+            Some(encoded) if self.items.contains(&encoded) => {
+                if !self.added.remove(&encoded) {
+                    self.removed.insert(encoded.clone());
+                }
+                self.items.remove(&encoded)
+            },
+            _ => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.added.clear();
+        self.removed.clear();
+        self.cleared = true;
+    }
+}
+
+impl<T: AnyMessage + Clone> Crdt for ORSet<T> {
+
+    fn apply_state(&mut self, state: CrdtState) -> Result<(), String> {
+        match state {
+            CrdtState::ORSet { items } => {
+                self.items = items.into_iter().collect();
+                Ok(())
+            },
+            other => Err(unexpected("ORSet", other.name())),
+        }
+    }
+
+    fn apply_delta(&mut self, delta: CrdtDelta) -> Result<(), String> {
+        match delta {
+            CrdtDelta::ORSet { cleared, removed, added } => {
+                if cleared {
+                    self.items.clear();
+                }
+                for item in removed.iter() {
+                    self.items.remove(item);
+                }
+                self.items.extend(added);
+                Ok(())
+            },
+            other => Err(unexpected("ORSet", other.name())),
+        }
+    }
+
+    fn state(&self) -> CrdtState {
+        CrdtState::ORSet { items: self.items.iter().cloned().collect() }
+    }
+
+    fn take_delta(&mut self) -> Option<CrdtDelta> {
+        if !self.cleared && self.removed.is_empty() && self.added.is_empty() {
+            return None;
+        }
+        Some(CrdtDelta::ORSet {
+            cleared: std::mem::take(&mut self.cleared),
+            removed: std::mem::take(&mut self.removed).into_iter().collect(),
+            added: std::mem::take(&mut self.added).into_iter().collect(),
+        })
+    }
+}
+
+// A Last-Write-Wins Register
+//TODO support custom clocks, only the default clock is used for now
+#[derive(Clone, Debug)]
+pub struct LWWRegister<T> {
+    value: Option<(String, Bytes)>,
+    changed: bool,
+    value_type: PhantomData<T>,
+}
+
+impl<T> Default for LWWRegister<T> {
+    fn default() -> Self {
+        LWWRegister {
+            value: None,
+            changed: false,
+            value_type: PhantomData,
+        }
+    }
+}
+
+impl<T: AnyMessage> LWWRegister<T> {
+
+    pub fn get(&self) -> Option<T> {
+        decode_items(self.value.iter()).next()
+    }
+
+    pub fn set(&mut self, value: T) {
+        if let Some(encoded) = encode_item(&value) {
+            if self.value.as_ref() != Some(&encoded) {
+                self.value = Some(encoded);
+                self.changed = true;
+            }
+        }
+    }
+}
+
+impl<T: AnyMessage + Clone> Crdt for LWWRegister<T> {
+
+    fn apply_state(&mut self, state: CrdtState) -> Result<(), String> {
+        match state {
+            CrdtState::LWWRegister { value } => {
+                self.value = value;
+                Ok(())
+            },
+            other => Err(unexpected("LWWRegister", other.name())),
+        }
+    }
+
+    fn apply_delta(&mut self, delta: CrdtDelta) -> Result<(), String> {
+        match delta {
+            CrdtDelta::LWWRegister { value } => {
+                self.value = value;
+                Ok(())
+            },
+            other => Err(unexpected("LWWRegister", other.name())),
+        }
+    }
+
+    fn state(&self) -> CrdtState {
+        CrdtState::LWWRegister { value: self.value.clone() }
+    }
+
+    fn take_delta(&mut self) -> Option<CrdtDelta> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        Some(CrdtDelta::LWWRegister { value: self.value.clone() })
+    }
+}
+
+// A Flag that can only be switched from false to true
+#[derive(Clone, Default, Debug)]
+pub struct Flag {
+    value: bool,
+    changed: bool,
+}
+
+impl Flag {
+
+    pub fn is_enabled(&self) -> bool {
+        self.value
+    }
+
+    pub fn enable(&mut self) {
+        if !self.value {
+            self.value = true;
+            self.changed = true;
+        }
+    }
+}
+
+impl Crdt for Flag {
+
+    fn apply_state(&mut self, state: CrdtState) -> Result<(), String> {
+        match state {
+            CrdtState::Flag { value } => {
+                self.value = value;
+                Ok(())
+            },
+            other => Err(unexpected("Flag", other.name())),
+        }
+    }
+
+    fn apply_delta(&mut self, delta: CrdtDelta) -> Result<(), String> {
+        match delta {
+            CrdtDelta::Flag { value } => {
+                self.value |= value;
+                Ok(())
+            },
+            other => Err(unexpected("Flag", other.name())),
+        }
+    }
+
+    fn state(&self) -> CrdtState {
+        CrdtState::Flag { value: self.value }
+    }
+
+    fn take_delta(&mut self) -> Option<CrdtDelta> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        Some(CrdtDelta::Flag { value: self.value })
+    }
+}
+
+// A Vote of all the nodes running the entity
+#[derive(Clone, Default, Debug)]
+pub struct Vote {
+    votes_for: u32,
+    total_voters: u32,
+    self_vote: bool,
+    changed: bool,
+}
+
+impl Vote {
+
+    pub fn self_vote(&self) -> bool {
+        self.self_vote
+    }
+
+    pub fn votes_for(&self) -> u32 {
+        self.votes_for
+    }
+
+    pub fn voters(&self) -> u32 {
+        self.total_voters
+    }
+
+    pub fn at_least_one(&self) -> bool {
+        self.votes_for > 0
+    }
+
+    pub fn majority(&self) -> bool {
+        self.votes_for > self.total_voters / 2
+    }
+
+    pub fn all(&self) -> bool {
+        self.votes_for == self.total_voters
+    }
+
+    pub fn vote(&mut self, vote: bool) {
+        if self.self_vote != vote {
+            // The state from the proxy may be inconsistent, e.g. a self vote without any votes
+            if vote {
+                self.votes_for = self.votes_for.saturating_add(1);
+            } else {
+                self.votes_for = self.votes_for.saturating_sub(1);
+            }
+            self.self_vote = vote;
+            self.changed = !self.changed;
+        }
+    }
+}
+
+impl Crdt for Vote {
+
+    fn apply_state(&mut self, state: CrdtState) -> Result<(), String> {
+        match state {
+            CrdtState::Vote { votes_for, total_voters, self_vote } => {
+                self.votes_for = votes_for;
+                self.total_voters = total_voters;
+                self.self_vote = self_vote;
+                Ok(())
+            },
+            other => Err(unexpected("Vote", other.name())),
+        }
+    }
+
+    fn apply_delta(&mut self, delta: CrdtDelta) -> Result<(), String> {
+        match delta {
+            CrdtDelta::Vote { votes_for, total_voters, .. } => {
+                self.votes_for = votes_for;
+                self.total_voters = total_voters;
+                Ok(())
+            },
+            other => Err(unexpected("Vote", other.name())),
+        }
+    }
+
+    fn state(&self) -> CrdtState {
+        CrdtState::Vote {
+            votes_for: self.votes_for,
+            total_voters: self.total_voters,
+            self_vote: self.self_vote,
+        }
+    }
+
+    fn take_delta(&mut self) -> Option<CrdtDelta> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        Some(CrdtDelta::Vote {
+            self_vote: self.self_vote,
+            votes_for: 0,
+            total_voters: 0,
+        })
+    }
+}
+
+pub enum CrdtStateAction {
+    Create(CrdtState),
+    Update(CrdtDelta),
+    Delete,
+}
+
+pub struct CrdtEntityResponse {
+    pub action: EntityAction,
+    pub state_action: Option<CrdtStateAction>,
+    // The streamed command has been accepted, see `CrdtCommandContext::accept_stream`
+    pub streamed: bool,
+    // The messages of the other streamed commands since the command changed the state
+    pub streamed_messages: Vec<CrdtStreamedMessage>,
+}
+
+// A message sent to the client of an accepted streamed command
+pub struct CrdtStreamedMessage {
+    pub command_id: i64,
+    pub action: EntityAction,
+    // No message is sent for the command after this one
+    pub end_stream: bool,
+}
+
+pub struct StreamCancelledResponse {
+    pub state_action: Option<CrdtStateAction>,
+    pub streamed_messages: Vec<CrdtStreamedMessage>,
+}
+
+// What a streamed command sends to its client when the state changes, see `CrdtEntity::state_changed`
+pub enum StreamedResponse<T: AnyMessage> {
+    Message(T),
+    // Nothing to send this time, e.g. the part of the state watched by the client hasn't changed
+    NoMessage,
+    // Ends the stream, after the message if there is one
+    End(Option<T>),
+}
+
+pub trait CrdtCommandContext<S: Crdt> {
+
+    // Returns None if the entity doesn't have a state yet or it has been deleted
+    fn state(&self) -> Option<&S>;
+
+    // Creates a new state if the entity doesn't have it yet
+    fn state_mut(&mut self) -> &mut S;
+
+    fn delete(&mut self);
+
+    // Whether the client of the command expects a stream of messages
+    fn streamed(&self) -> bool;
+
+    // Keeps the streamed command open once it's replied to, see `CrdtEntity::state_changed`.
+    // It's ignored if the command isn't streamed or if it fails.
+    fn accept_stream(&mut self);
+}
+
+struct CrdtCommandContextData<'a, S> {
+    state: &'a mut Option<S>,
+    created: bool,
+    deleted: bool,
+    streamed: bool,
+    stream_accepted: bool,
+}
+
+impl<'a, S: Crdt> CrdtCommandContextData<'a, S> {
+
+    fn new(state: &'a mut Option<S>, streamed: bool) -> CrdtCommandContextData<'a, S> {
+        CrdtCommandContextData {
+            state,
+            created: false,
+            deleted: false,
+            streamed,
+            stream_accepted: false,
+        }
+    }
+
+    // The changes made to the state, to be sent to the proxy
+    fn take_state_action(self) -> Option<CrdtStateAction> {
+        match self.state {
+            _ if self.deleted => Some(CrdtStateAction::Delete),
+            Some(state) if self.created => {
+                state.take_delta();
+                Some(CrdtStateAction::Create(state.state()))
+            },
+            Some(state) => state.take_delta().map(CrdtStateAction::Update),
+            None => None,
+        }
+    }
+}
+
+impl<'a, S: Crdt> CrdtCommandContext<S> for CrdtCommandContextData<'a, S> {
+
+    fn state(&self) -> Option<&S> {
+        self.state.as_ref()
+    }
+
+    fn state_mut(&mut self) -> &mut S {
+        if self.state.is_none() {
+            self.created = true;
+            self.deleted = false;
+        }
+        self.state.get_or_insert_with(S::default)
+    }
+
+    fn delete(&mut self) {
+        *self.state = None;
+        self.created = false;
+        self.deleted = true;
+    }
+
+    fn streamed(&self) -> bool {
+        self.streamed
+    }
+
+    fn accept_stream(&mut self) {
+        self.stream_accepted = self.streamed;
+    }
+}
+
+// this is typed CRDT entity interface to be implemented by user
+// The CRDT state is held by the library on behalf of the entity and passed to the command handler via the context.
+pub trait CrdtEntity {
+
+    type Command : AnyMessage;
+    type Response : AnyMessage;
+    // Entity is backed by exactly one type of CRDT
+    type State : Crdt;
+
+    fn handle_command(&self, command: Self::Command, context: &mut impl CrdtCommandContext<Self::State>) -> Result<Response<Self::Response>, CommandError>;
+
+    // Called for each accepted streamed command whenever the state changes, either by a command or by the proxy,
+    // e.g. to let the client watch a counter. The state is None once the entity is deleted.
+    fn state_changed(&self, _command: &Self::Command, _state: Option<&Self::State>) -> StreamedResponse<Self::Response> {
+        StreamedResponse::NoMessage
+    }
+
+    // The client cancelled the accepted streamed command, the state can still be changed, e.g. to clean up
+    fn stream_cancelled(&self, _command: Self::Command, _context: &mut impl CrdtCommandContext<Self::State>) {}
+}
+
+// this is untyped CRDT entity handler interface for the server implementation
+// The state changes made by the proxy return the messages of the streamed commands.
+pub trait CrdtEntityHandler {
+    fn state_received(&mut self, state: CrdtState) -> Result<Vec<CrdtStreamedMessage>, String>;
+    fn delta_received(&mut self, delta: CrdtDelta) -> Result<Vec<CrdtStreamedMessage>, String>;
+    fn deleted(&mut self) -> Vec<CrdtStreamedMessage>;
+    fn command_received(&mut self, command_id: i64, command_name: &str, streamed: bool, type_url: &str, bytes: Bytes) -> CrdtEntityResponse;
+    fn stream_cancelled(&mut self, command_id: i64) -> StreamCancelledResponse;
+}
+
+// Binds the user's typed CrdtEntity to the CRDT state that's kept on its behalf
+struct CrdtEntityInstance<E: CrdtEntity> {
+    entity: E,
+    state: Option<E::State>,
+    // The accepted streamed commands by command id
+    streams: BTreeMap<i64, E::Command>,
+}
+
+impl<E: CrdtEntity> CrdtEntityInstance<E> {
+
+    fn state_changed(&mut self) -> Vec<CrdtStreamedMessage> {
+        let CrdtEntityInstance { entity, state, streams } = self;
+        let mut messages = vec![];
+        streams.retain(|&command_id, command| {
+            let (reply, end_stream) = match entity.state_changed(command, state.as_ref()) {
+                StreamedResponse::Message(reply) => (Some(reply), false),
+                StreamedResponse::NoMessage => return true,
+                StreamedResponse::End(reply) => (reply, true),
+            };
+            let action = match reply {
+                Some(reply) => EntityAction::from_result(Ok(Response::Reply(reply))),
+                None => EntityAction::NoReply,
+            };
+            messages.push(CrdtStreamedMessage { command_id, action, end_stream });
+            !end_stream
+        });
+        messages
+    }
+}
+
+impl<E: CrdtEntity> CrdtEntityHandler for CrdtEntityInstance<E> {
+
+    fn state_received(&mut self, state: CrdtState) -> Result<Vec<CrdtStreamedMessage>, String> {
+        let mut new_state = E::State::default();
+        new_state.apply_state(state)?;
+        self.state = Some(new_state);
+        Ok(self.state_changed())
+    }
+
+    fn delta_received(&mut self, delta: CrdtDelta) -> Result<Vec<CrdtStreamedMessage>, String> {
+        match &mut self.state {
+            Some(state) => state.apply_delta(delta)?,
+            None => return Err(format!("Received {} delta before the state", delta.name())),
+        }
+        Ok(self.state_changed())
+    }
+
+    fn deleted(&mut self) -> Vec<CrdtStreamedMessage> {
+        self.state = None;
+        self.state_changed()
+    }
+
+    fn command_received(&mut self, command_id: i64, command_name: &str, streamed: bool, type_url: &str, bytes: Bytes) -> CrdtEntityResponse {
+        debug!(type_url, "Handling received command");
+        let cmd = match <E::Command as AnyMessage>::decode_command(command_name, type_url, &bytes[..]) {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                warn!(error = %err, "Couldn't decode command");
+                return CrdtEntityResponse {
+                    action: EntityAction::Failure(CommandError::Decode { type_url: type_url.to_owned() }),
+                    state_action: None,
+                    streamed: false,
+                    streamed_messages: vec![],
+                };
+            },
+        };
+
+        // keep the state to roll back to if the command fails
+        let previous_state = self.state.clone();
+
+        let mut context = CrdtCommandContextData::new(&mut self.state, streamed);
+
+        let result = self.entity.handle_command(cmd, &mut context);
+
+        let stream_accepted = context.stream_accepted;
+        let state_action = match result {
+            Ok(_) => context.take_state_action(),
+            Err(_) => {
+                self.state = previous_state;
+                None
+            },
+        };
+
+        // The command gets the change it made with its reply, so it's only streamed the next ones
+        let streamed_messages = match state_action {
+            Some(_) => self.state_changed(),
+            None => vec![],
+        };
+
+        // The command is kept as it is, so it's decoded once more instead of requiring it to be Clone
+        let streamed = stream_accepted && result.is_ok();
+        if streamed {
+            match <E::Command as AnyMessage>::decode_command(command_name, type_url, bytes) {
+                Ok(cmd) => {
+                    self.streams.insert(command_id, cmd);
+                },
+                Err(err) => warn!(error = %err, "Couldn't decode streamed command"),
+            }
+        }
+
+        CrdtEntityResponse {
+            action: EntityAction::from_result(result),
+            state_action,
+            streamed,
+            streamed_messages,
+        }
+    }
+
+    fn stream_cancelled(&mut self, command_id: i64) -> StreamCancelledResponse {
+        let cmd = match self.streams.remove(&command_id) {
+            Some(cmd) => cmd,
+            // The stream has already ended
+            None => return StreamCancelledResponse {
+                state_action: None,
+                streamed_messages: vec![],
+            },
+        };
+
+        let mut context = CrdtCommandContextData::new(&mut self.state, false);
+        self.entity.stream_cancelled(cmd, &mut context);
+        let state_action = context.take_state_action();
+
+        let streamed_messages = match state_action {
+            Some(_) => self.state_changed(),
+            None => vec![],
+        };
+
+        StreamCancelledResponse {
+            state_action,
+            streamed_messages,
+        }
+    }
+}
+
+pub struct CrdtEntityDescriptor {
+    pub service_name: String,
+    pub message_types: MessageTypes,
+    handler_factory: Box<dyn Fn() -> Box<dyn CrdtEntityHandler + Send + Sync> + Send + Sync>,
+}
+
+impl CrdtEntityDescriptor {
+
+    pub(crate) fn new<F, E>(service_name: &str, entity_factory: F) -> CrdtEntityDescriptor
+        where F: Fn () -> E + Send + Sync + 'static,
+              E: CrdtEntity + Send + Sync + 'static,
+              E::State: Send + Sync + 'static,
+              E::Command: Send + Sync + 'static,
+    {
+        CrdtEntityDescriptor {
+            service_name: service_name.to_owned(),
+            message_types: MessageTypes::of::<E::Command, E::Response>(),
+            handler_factory: Box::new(move || {
+                Box::new(CrdtEntityInstance {
+                    entity: entity_factory(),
+                    state: None,
+                    streams: BTreeMap::new(),
+                })
+            }),
+        }
+    }
+
+    pub fn create(&self) -> Box<dyn CrdtEntityHandler + Send + Sync> {
+        (self.handler_factory)()
+    }
+}
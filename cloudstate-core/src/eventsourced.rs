@@ -1,267 +1,691 @@
-use bytes::Bytes;
-use crate::AnyMessage;
+use std::sync::Arc;
+use async_trait::async_trait;
+use tracing::{debug, warn};
+use crate::{AnyMessage, MessageError, MessageTypes, SideEffect};
 
-pub struct EntityRegistry {
-    pub event_sourced_entities: Vec<EventSourcedEntityDescriptor>,
-}
+pub use crate::{CommandError, EntityAction, Response};
+
+type EventSourcedHandlerFactory = dyn Fn(&str) -> Box<dyn EventSourcedEntityHandler + Send + Sync> + Send + Sync;
 
 pub struct EventSourcedEntityDescriptor {
     pub service_name: String,
     pub persistence_id : String,
-    handler_factory: Box<dyn Fn() -> Box<dyn EventSourcedEntityHandler + Send + Sync> + Send + Sync>,
+    pub message_types: MessageTypes,
+    handler_factory: Box<EventSourcedHandlerFactory>,
+    snapshot_policy: Arc<dyn SnapshotPolicy>,
 }
 
-impl EntityRegistry {
+impl EventSourcedEntityDescriptor {
 
-    pub fn new() -> EntityRegistry {
-        EntityRegistry {
-            event_sourced_entities: vec![],
-        }
-    }
-
-    pub fn register_event_sourced_entity<F, H>(&mut self, service_name: &str, persistence_id: &str, handler_factory: F)
-        where F: Fn () -> H + Send + Sync + 'static,
+    pub(crate) fn new<F, H>(service_name: &str, persistence_id: &str, handler_factory: F) -> EventSourcedEntityDescriptor
+        where F: Fn (&str) -> H + Send + Sync + 'static,
               H: EventSourcedEntityHandler + Send + Sync + 'static
     {
-        if self.event_sourced_entities.iter().find(|v| v.service_name == service_name).is_some() {
-            panic!("Event sourced entity {} already registered!", service_name);
+        EventSourcedEntityDescriptor {
+            service_name: service_name.to_owned(),
+            persistence_id: persistence_id.to_owned(),
+            message_types: H::message_types(),
+            handler_factory: Box::new(move |entity_id| {
+                Box::new(handler_factory(entity_id))
+            }),
+            snapshot_policy: Arc::new(NeverSnapshot),
         }
+    }
 
-        let entity_name = service_name.to_owned();
-        let persistence_id = persistence_id.to_owned();
+    // The entity is never snapshotted by default
+    pub fn set_snapshot_policy<P: SnapshotPolicy + 'static>(&mut self, snapshot_policy: P) -> &mut EventSourcedEntityDescriptor {
+        self.snapshot_policy = Arc::new(snapshot_policy);
+        self
+    }
 
-        let create_entity_function = EventSourcedEntityDescriptor {
-            service_name: entity_name,
-            persistence_id,
-            handler_factory: Box::new(move || {
-                Box::new(handler_factory())
-            }),
-        };
-        self.event_sourced_entities.push(create_entity_function);
+    pub fn snapshot_policy(&self) -> Arc<dyn SnapshotPolicy> {
+        self.snapshot_policy.clone()
     }
 
-    pub fn create(&self, entity_name: &str) -> Option<Box<dyn EventSourcedEntityHandler + Send + Sync>> {
-        for factory in &self.event_sourced_entities {
-            if factory.service_name == entity_name {
-                let f = &factory.handler_factory;
-                return Some(f())
-            }
+    pub fn create(&self, entity_id: &str) -> Box<dyn EventSourcedEntityHandler + Send + Sync> {
+        (self.handler_factory)(entity_id)
+    }
+}
+
+// Decides whether the entity state is snapshotted once a command has emitted events
+pub trait SnapshotPolicy: Send + Sync {
+    fn should_snapshot(&self, context: &mut SnapshotContext) -> bool;
+}
+
+pub struct SnapshotContext<'a> {
+    // The sequence number of the last event before the command
+    pub previous_sequence: i64,
+    // The sequence number of the last event emitted by the command
+    pub sequence: i64,
+    pub events: &'a [(String, Vec<u8>)],
+    take_snapshot: &'a mut dyn FnMut() -> Option<(String, Vec<u8>)>,
+    snapshot: Option<Option<(String, Vec<u8>)>>,
+}
+
+impl<'a> SnapshotContext<'a> {
+
+    pub fn new(previous_sequence: i64,
+               events: &'a [(String, Vec<u8>)],
+               take_snapshot: &'a mut dyn FnMut() -> Option<(String, Vec<u8>)>) -> SnapshotContext<'a> {
+        SnapshotContext {
+            previous_sequence,
+            sequence: previous_sequence + events.len() as i64,
+            events,
+            take_snapshot,
+            snapshot: None,
+        }
+    }
+
+    // The snapshot is encoded at most once, so the policy can look at it without wasting the work
+    pub fn snapshot(&mut self) -> Option<&(String, Vec<u8>)> {
+        if self.snapshot.is_none() {
+            self.snapshot = Some((self.take_snapshot)());
         }
-        return None;
+        self.snapshot.as_ref().and_then(|v| v.as_ref())
+    }
+
+    fn into_snapshot(mut self) -> Option<(String, Vec<u8>)> {
+        self.snapshot();
+        self.snapshot.and_then(|v| v)
+    }
+}
+
+pub struct NeverSnapshot;
+
+impl SnapshotPolicy for NeverSnapshot {
+    fn should_snapshot(&self, _context: &mut SnapshotContext) -> bool {
+        false
+    }
+}
+
+// Snapshots whenever the sequence reaches or jumps over a multiple of N,
+// so a command emitting several events doesn't skip the snapshot.
+pub struct EveryNEvents(pub u32);
+
+impl SnapshotPolicy for EveryNEvents {
+    fn should_snapshot(&self, context: &mut SnapshotContext) -> bool {
+        let every = i64::from(self.0);
+        every > 0 && context.sequence.div_euclid(every) > context.previous_sequence.div_euclid(every)
+    }
+}
+
+// Snapshots when the command emits an event of one of the type URLs
+pub struct OnEventType(pub Vec<String>);
+
+impl SnapshotPolicy for OnEventType {
+    fn should_snapshot(&self, context: &mut SnapshotContext) -> bool {
+        context.events.iter().any(|(type_url, _)| self.0.contains(type_url))
+    }
+}
+
+// Snapshots when the encoded state is at least that many bytes
+pub struct StateSizeThreshold(pub usize);
+
+impl SnapshotPolicy for StateSizeThreshold {
+    fn should_snapshot(&self, context: &mut SnapshotContext) -> bool {
+        context.snapshot().is_some_and(|(_, bytes)| bytes.len() >= self.0)
     }
 }
 
 pub trait CommandContext<T: AnyMessage> {
-    fn emit_event(&mut self, event: T);
+    // Takes the event or its message when the event enum can be built from it
+    fn emit_event(&mut self, event: impl Into<T>);
+
+    // Forwards the command to another service.
+    // The command handler is expected to return `Response::NoReply` when the command is forwarded.
+    fn forward<M: AnyMessage>(&mut self, service_name: &str, command_name: &str, message: &M);
+
+    // Invokes another service once the command has been successfully handled.
+    // A synchronous side effect has to complete before the reply is sent to the client.
+    fn effect<M: AnyMessage>(&mut self, service_name: &str, command_name: &str, message: &M, synchronous: bool);
+
+    fn entity_id(&self) -> &str;
+
+    fn command_id(&self) -> i64;
+
+    // The name of the RPC method the command has been sent to
+    fn command_name(&self) -> &str;
+
+    // The sequence number of the last event applied to the entity before the command,
+    // the events emitted by the command get the following numbers.
+    fn sequence(&self) -> i64;
+}
+
+// Why the entity is passivated, see `EventSourcedEntity::on_passivate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Passivation {
+    // The proxy closed the stream, e.g. the entity has been idle for a while
+    Closed,
+    // Either the stream or the entity failed
+    Failed(String),
+    // The stream went away without being closed, e.g. the proxy disconnected abruptly
+    Dropped,
+}
+
+// What the server knows about a command besides its payload
+#[derive(Debug, Clone, Copy)]
+pub struct CommandMetadata<'a> {
+    pub entity_id: &'a str,
+    pub command_id: i64,
+    pub command_name: &'a str,
+    pub sequence: i64,
 }
 
-struct CommandContextData<T> {
-    events: Vec<T>,
+struct ServiceCall {
+    service_name: String,
+    command_name: String,
+    type_url: String,
+    bytes: Vec<u8>,
 }
 
-impl<T: AnyMessage> CommandContext<T> for CommandContextData<T> {
+// Gives the command handler access to the entity state including the events emitted so far.
+// The events are only applied on emit when the entity provides a working copy, see `EventSourcedEntity::working_copy`.
+pub trait EntityCommandContext<E: AsyncEventSourcedEntity>: CommandContext<E::Event> {
+    fn entity(&self) -> &E;
+}
+
+struct CommandContextData<'a, E: AsyncEventSourcedEntity> {
+    entity: &'a E,
+    metadata: CommandMetadata<'a>,
+    // The events are applied to the working copy as soon as they are emitted
+    working_copy: Option<E>,
+    // The events to apply to the entity once the command succeeds when there's no working copy
+    applied_events: Vec<E::Event>,
+    events: Vec<(String, Vec<u8>)>,
+    verify_events: bool,
+    forward: Option<ServiceCall>,
+    side_effects: Vec<SideEffect>,
+    // A failure occurred while emitting an event or preparing a forward or a side effect
+    failure: Option<CommandError>,
+}
 
-    fn emit_event(&mut self, event: T) {
-        //TODO how to call an event handler from here?
-        // it might be useful if we would like to apply events
-        // as soon as they are emitted
-        self.events.push(event);
+impl<'a, E: AsyncEventSourcedEntity> CommandContextData<'a, E> {
+
+    fn new(entity: &'a E, metadata: CommandMetadata<'a>) -> CommandContextData<'a, E> {
+        CommandContextData {
+            entity,
+            metadata,
+            working_copy: entity.working_copy(),
+            applied_events: vec![],
+            events: vec![],
+            verify_events: entity.verify_events(),
+            forward: None,
+            side_effects: vec![],
+            failure: None,
+        }
+    }
+
+    fn service_call<M: AnyMessage>(&mut self, service_name: &str, command_name: &str, message: &M) -> Option<ServiceCall> {
+        match message.encode() {
+            Ok((type_url, bytes)) => Some(ServiceCall {
+                service_name: service_name.to_owned(),
+                command_name: command_name.to_owned(),
+                type_url,
+                bytes,
+            }),
+            Err(err) => {
+                warn!(error = %err, "Couldn't encode the message for {}.{}", service_name, command_name);
+                self.failure = Some(CommandError::Encode(format!("the message for {}.{}", service_name, command_name)));
+                None
+            },
+        }
     }
 }
 
-pub enum Response<T: AnyMessage> {
-    Reply(T),
-    EmptyReply,
-    // Forward,
-    // NoReply,
+impl<'a, E: AsyncEventSourcedEntity> CommandContext<E::Event> for CommandContextData<'a, E> {
+
+    fn emit_event(&mut self, event: impl Into<E::Event>) {
+        let event = event.into();
+        match <E::Event as AnyMessage>::encode(&event) {
+            Ok((type_url, bytes)) => {
+                if self.verify_events {
+                    if let Err(err) = verify_round_trip(&event, &type_url, &bytes) {
+                        warn!(error = %err, "The event doesn't survive the round trip");
+                        if self.failure.is_none() {
+                            self.failure = Some(CommandError::Internal(err.to_string()));
+                        }
+                        return;
+                    }
+                }
+                // The typed event is applied as is, it's only encoded for the proxy
+                match self.working_copy.as_mut() {
+                    Some(working_copy) => working_copy.handle_event(event),
+                    None => self.applied_events.push(event),
+                }
+                self.events.push((type_url, bytes));
+            },
+            // The whole command fails, none of its events are persisted
+            Err(err) => {
+                warn!(error = %err, "Couldn't encode an event");
+                if self.failure.is_none() {
+                    self.failure = Some(CommandError::Encode("an event".to_owned()));
+                }
+            },
+        }
+    }
+
+    fn forward<M: AnyMessage>(&mut self, service_name: &str, command_name: &str, message: &M) {
+        if self.forward.is_some() {
+            self.failure = Some(CommandError::Internal("the command has already been forwarded".to_owned()));
+            return;
+        }
+        self.forward = self.service_call(service_name, command_name, message);
+    }
+
+    fn effect<M: AnyMessage>(&mut self, service_name: &str, command_name: &str, message: &M, synchronous: bool) {
+        if let Some(call) = self.service_call(service_name, command_name, message) {
+            self.side_effects.push(SideEffect {
+                service_name: call.service_name,
+                command_name: call.command_name,
+                type_url: call.type_url,
+                bytes: call.bytes,
+                synchronous,
+            });
+        }
+    }
+
+    fn entity_id(&self) -> &str {
+        self.metadata.entity_id
+    }
+
+    fn command_id(&self) -> i64 {
+        self.metadata.command_id
+    }
+
+    fn command_name(&self) -> &str {
+        self.metadata.command_name
+    }
+
+    fn sequence(&self) -> i64 {
+        self.metadata.sequence
+    }
+}
+
+impl<'a, E: AsyncEventSourcedEntity> EntityCommandContext<E> for CommandContextData<'a, E> {
+
+    fn entity(&self) -> &E {
+        self.working_copy.as_ref().unwrap_or(self.entity)
+    }
+}
+
+// Checks that the encoded event decodes back to the same event, e.g. a hand-written `AnyMessage`
+// decodes what it encodes. The events are compared rather than their bytes,
+// prost encodes the entries of a map in the order of its HashMap.
+fn verify_round_trip<T: AnyMessage + PartialEq>(event: &T, type_url: &str, bytes: &[u8]) -> Result<(), MessageError> {
+    if T::decode(type_url, bytes)? != *event {
+        return Err(MessageError::decode(type_url, "the event is different once decoded"));
+    }
+    Ok(())
 }
 
 // this is typed entity handler interface to be implemented by user
 // NOTE: it can't be used by the server side as-is because it has associated types.
 //  Such traits can't be used as trait objects.
-pub trait EventSourcedEntity {
+pub trait EventSourcedEntity: Send + Sync + Sized {
 
     // Entity can only have one type of snapshot thus it's an associated type instead of a trait's type parameter
-    type Command : AnyMessage;
-    type Event : AnyMessage;
+    type Command : AnyMessage + Send;
+    type Event : AnyMessage + PartialEq + Send;
     type Snapshot : AnyMessage;
     type Response : AnyMessage;
 
     // This method is called by server and need to bind to the entity typed and delegate call to the user implementation
-    fn snapshot_received(&mut self, type_url: &str, bytes: Bytes) {
-        if let Some(snapshot) = <Self::Snapshot as AnyMessage>::decode(&type_url, bytes) {
-            println!("Received snapshot!");
-            self.handle_snapshot(snapshot);
-        } else {
-            eprintln!("Couldn't decode snapshot!");
+    fn snapshot_received(&mut self, type_url: &str, bytes: &[u8]) {
+        match <Self::Snapshot as AnyMessage>::decode(type_url, bytes) {
+            Ok(snapshot) => {
+                debug!(type_url, "Received snapshot");
+                self.handle_snapshot(snapshot);
+            },
+            Err(err) => warn!(error = %err, "Couldn't decode snapshot"),
         }
     }
 
     fn handle_snapshot(&mut self, snapshot: Self::Snapshot);
 
-    fn snapshot_every(&self) -> Option<u32> {
+    // Called once the entity is initialized, after the snapshot if there is one
+    fn on_init(&mut self, _entity_id: &str) {}
+
+    // Called before the first command once the events have been replayed, with the sequence of the last one
+    fn on_recovered(&mut self, _sequence: i64) {}
+
+    // Called once when the entity goes away however its stream ends, e.g. to release the resources it holds
+    fn on_passivate(&mut self, _passivation: Passivation) {}
+
+    // When it's taken is up to the snapshot policy of the entity registration, see `SnapshotPolicy`
+    fn take_snapshot(&self) -> Option<Self::Snapshot> {
         None
     }
 
-    //TODO maybe extract snapshot taking into a separate trait to be able avoid implementing it?
-    fn take_snapshot(&self) -> Option<Self::Snapshot> {
+    // Opt-in to apply the events as soon as they are emitted, usually by returning `Some(self.clone())`.
+    // The command handler then observes the new state through `EntityCommandContext::entity`.
+    // The working copy replaces the entity if the command succeeds and it's dropped otherwise.
+    fn working_copy(&self) -> Option<Self> {
         None
     }
 
-    fn command_received(&mut self, type_url: &str, bytes: Bytes, snapshot_sequence: i64) -> EntityResponse {
-        println!("Handing received command {}", &type_url);
-        if let Some(cmd) = <Self::Command as AnyMessage>::decode(&type_url, bytes) {
+    // Checks that every emitted event decodes back to the same event, the command fails otherwise.
+    // It catches the schema mistakes early at the cost of another decode, so it's only on in debug builds.
+    fn verify_events(&self) -> bool {
+        cfg!(debug_assertions)
+    }
 
-            let mut context = CommandContextData::<Self::Event> {
-                events: vec![],
-            };
-            //TODO pass event_handler to be called immediately on emit_event
-            // doesn't seem to be working approach and Java client and some other implementations
-            // are seem to be broken. The problem is that if after an event was emitted
-            // it return a failure. In that case it doesn't rollback to the previous state.
+    // The context can also be taken as `&mut impl CommandContext<Self::Event>` when the entity isn't needed
+    fn handle_command(&self, command: Self::Command, context: &mut impl EntityCommandContext<Self>) -> Result<Response<Self::Response>, CommandError>;
 
-            let result = self.handle_command(cmd, &mut context);
+    fn event_received(&mut self, type_url: &str, bytes: &[u8]) {
+        debug!(type_url, "Handling received event");
 
-            let events: Vec<(String, Bytes)> = match result {
-                Ok(_) => {
-                    context.events.iter().flat_map(|e| {
-                        match <Self::Event as AnyMessage>::encode(&e) {
-                            Some((type_id, bytes)) => Some((type_id, Bytes::from(bytes))),
-                            _ => None,
-                        }
-                    }).collect()
-                },
-                Err(_) => {
-                    vec![]
-                },
-            };
+        match <Self::Event as AnyMessage>::decode(type_url, bytes) {
+            Ok(evt) => self.handle_event(evt),
+            //TODO what to do if can't deserialize event?
+            Err(err) => warn!(error = %err, "Couldn't decode event"),
+        }
+    }
 
-            for (type_url, bytes) in events.iter() {
-                self.event_received(&type_url, bytes.clone());
-            }
+    fn handle_event(&mut self, event: Self::Event);
+}
 
-            let mut snapshot: Option<(String, Vec<u8>)> = None;
-
-            if let Some(snapshot_every) = self.snapshot_every() {
-                let new_snapshot_sequence = snapshot_sequence + events.len() as i64;
-                if new_snapshot_sequence % snapshot_every as i64 == 0 {
-                    //TODO prepare snapshot
-                    if let Some(s) = self.take_snapshot() {
-                        snapshot = <Self::Snapshot as AnyMessage>::encode(&s);
-                        //TODO log serialization error if it occurs
-                    } else {
-                        //TODO log that snapshot hasn't been given
-                    }
-                }
-            }
+// The same as EventSourcedEntity but the commands are handled asynchronously,
+// so the command handler can await without blocking the server.
+// Every EventSourcedEntity is an AsyncEventSourcedEntity as well, so the server only deals with this one.
+#[async_trait]
+pub trait AsyncEventSourcedEntity: Send + Sync + Sized {
 
-            //TODO return an effect to be sent to Akka
+    type Command : AnyMessage + Send;
+    type Event : AnyMessage + PartialEq + Send;
+    type Snapshot : AnyMessage;
+    type Response : AnyMessage;
 
-            let action: EntityAction = match result {
-                Ok(Response::Reply(resp)) => {
-                    match <Self::Response as AnyMessage>::encode(&resp) {
-                        Some((type_url, bytes)) => {
-                            EntityAction::Reply {
-                                type_url,
-                                bytes
-                            }
-                        }
-                        _ => {
-                            //TODO log an error
-                            EntityAction::Failure {
-                                msg: "Server error: couldn't encode the response".to_owned()
-                            }
-                        }
-                    }
-                },
-                Ok(Response::EmptyReply) => EntityAction::EmptyReply,
-                Err(msg) => {
-                    EntityAction::Failure {
-                        msg
-                    }
-                }
-            };
+    fn snapshot_received(&mut self, type_url: &str, bytes: &[u8]) {
+        match <Self::Snapshot as AnyMessage>::decode(type_url, bytes) {
+            Ok(snapshot) => {
+                debug!(type_url, "Received snapshot");
+                self.handle_snapshot(snapshot);
+            },
+            Err(err) => warn!(error = %err, "Couldn't decode snapshot"),
+        }
+    }
 
-            EntityResponse {
-                action,
-                events,
-                snapshot,
-            }
-        } else {
-            println!("Couldn't decode command {}", type_url);
-            EntityResponse {
-                action: EntityAction::Failure {
-                    msg: "Server error: couldn't encode the response".to_owned()
-                },
-                events: vec![],
-                snapshot: None,
-            }
+    fn handle_snapshot(&mut self, snapshot: Self::Snapshot);
+
+    // See EventSourcedEntity::on_init
+    fn on_init(&mut self, _entity_id: &str) {}
+
+    // See EventSourcedEntity::on_recovered
+    fn on_recovered(&mut self, _sequence: i64) {}
+
+    // See EventSourcedEntity::on_passivate
+    fn on_passivate(&mut self, _passivation: Passivation) {}
+
+    fn take_snapshot(&self) -> Option<Self::Snapshot> {
+        None
+    }
+
+    // See EventSourcedEntity::working_copy
+    fn working_copy(&self) -> Option<Self> {
+        None
+    }
+
+    // See EventSourcedEntity::verify_events
+    fn verify_events(&self) -> bool {
+        cfg!(debug_assertions)
+    }
+
+    async fn command_received(&mut self, type_url: &str, bytes: &[u8], metadata: CommandMetadata<'_>, snapshot_policy: &dyn SnapshotPolicy) -> EntityResponse {
+        debug!(type_url, "Handling received command");
+        match <Self::Command as AnyMessage>::decode_command(metadata.command_name, type_url, bytes) {
+            Ok(cmd) => {
+                let mut context = CommandContextData::new(&*self, metadata);
+                let result = self.handle_command(cmd, &mut context).await;
+                let CommandContextData { working_copy, applied_events, events, forward, side_effects, failure, .. } = context;
+                command_response(self, result, working_copy, applied_events, events, forward, side_effects, failure, metadata.sequence, snapshot_policy)
+            },
+            Err(err) => {
+                warn!(error = %err, "Couldn't decode command");
+                EntityResponse {
+                    action: EntityAction::Failure(CommandError::Decode { type_url: type_url.to_owned() }),
+                    events: vec![],
+                    snapshot: None,
+                    side_effects: vec![],
+                }
+            },
         }
     }
 
-    fn handle_command(&self, command: Self::Command, context: &mut impl CommandContext<Self::Event>) -> Result<Response<Self::Response>, String>;
+    async fn handle_command<C>(&self, command: Self::Command, context: &mut C) -> Result<Response<Self::Response>, CommandError>
+        where C: EntityCommandContext<Self> + Send;
 
-    fn event_received(&mut self, type_url: &str, bytes: Bytes) {
-        println!("Handling received event {}", type_url);
+    fn event_received(&mut self, type_url: &str, bytes: &[u8]) {
+        debug!(type_url, "Handling received event");
 
-        if let Some(evt) = <Self::Event as AnyMessage>::decode(type_url, bytes) {
-            self.handle_event(evt);
+        match <Self::Event as AnyMessage>::decode(type_url, bytes) {
+            Ok(evt) => self.handle_event(evt),
+            //TODO what to do if can't deserialize event?
+            Err(err) => warn!(error = %err, "Couldn't decode event"),
         }
-        //TODO what to do if can't deserialize event?
     }
 
     fn handle_event(&mut self, event: Self::Event);
 }
 
-//TODO maybe rename to ClientAction but it will overlap with the prototype name?
-pub enum EntityAction {
-    Reply {
-        type_url: String,
-        bytes: Vec<u8>,
-    },
-    EmptyReply,
-    Failure {
-        msg: String,
-    },
-    //TODO Forward,
+// This lets the server handle the sync entities the same way as the async ones
+#[async_trait]
+impl<T> AsyncEventSourcedEntity for T
+    where T: EventSourcedEntity {
+
+    type Command = <T as EventSourcedEntity>::Command;
+    type Event = <T as EventSourcedEntity>::Event;
+    type Snapshot = <T as EventSourcedEntity>::Snapshot;
+    type Response = <T as EventSourcedEntity>::Response;
+
+    #[inline]
+    fn snapshot_received(&mut self, type_url: &str, bytes: &[u8]) {
+        EventSourcedEntity::snapshot_received(self, type_url, bytes)
+    }
+
+    #[inline]
+    fn handle_snapshot(&mut self, snapshot: Self::Snapshot) {
+        EventSourcedEntity::handle_snapshot(self, snapshot)
+    }
+
+    #[inline]
+    fn on_init(&mut self, entity_id: &str) {
+        EventSourcedEntity::on_init(self, entity_id)
+    }
+
+    #[inline]
+    fn on_recovered(&mut self, sequence: i64) {
+        EventSourcedEntity::on_recovered(self, sequence)
+    }
+
+    #[inline]
+    fn on_passivate(&mut self, passivation: Passivation) {
+        EventSourcedEntity::on_passivate(self, passivation)
+    }
+
+    #[inline]
+    fn take_snapshot(&self) -> Option<Self::Snapshot> {
+        EventSourcedEntity::take_snapshot(self)
+    }
+
+    #[inline]
+    fn working_copy(&self) -> Option<Self> {
+        EventSourcedEntity::working_copy(self)
+    }
+
+    #[inline]
+    fn verify_events(&self) -> bool {
+        EventSourcedEntity::verify_events(self)
+    }
+
+    async fn handle_command<C>(&self, command: Self::Command, context: &mut C) -> Result<Response<Self::Response>, CommandError>
+        where C: EntityCommandContext<Self> + Send
+    {
+        EventSourcedEntity::handle_command(self, command, context)
+    }
+
+    #[inline]
+    fn event_received(&mut self, type_url: &str, bytes: &[u8]) {
+        EventSourcedEntity::event_received(self, type_url, bytes)
+    }
+
+    #[inline]
+    fn handle_event(&mut self, event: Self::Event) {
+        EventSourcedEntity::handle_event(self, event)
+    }
+}
+
+// Turns the result of the command handler and whatever it did with the context into the response for the server
+#[allow(clippy::too_many_arguments)]
+fn command_response<E: AsyncEventSourcedEntity>(entity: &mut E,
+                                               result: Result<Response<E::Response>, CommandError>,
+                                               working_copy: Option<E>,
+                                               applied_events: Vec<E::Event>,
+                                               events: Vec<(String, Vec<u8>)>,
+                                               forward: Option<ServiceCall>,
+                                               side_effects: Vec<SideEffect>,
+                                               failure: Option<CommandError>,
+                                               sequence: i64,
+                                               snapshot_policy: &dyn SnapshotPolicy) -> EntityResponse {
+    let result = match (result, failure) {
+        (Ok(_), Some(failure)) => Err(failure),
+        (Ok(Response::Reply(_)), _) | (Ok(Response::EmptyReply), _) if forward.is_some() => {
+            Err(CommandError::Internal("both a reply was returned and the command was forwarded, choose one or the other".to_owned()))
+        },
+        (result, _) => result,
+    };
+
+    let events = match result {
+        Ok(_) => events,
+        Err(_) => vec![],
+    };
+
+    // Nothing is applied to the entity if the command fails
+    if result.is_ok() {
+        match working_copy {
+            // The events have already been applied to the working copy
+            Some(working_copy) => *entity = working_copy,
+            None => {
+                for event in applied_events {
+                    entity.handle_event(event);
+                }
+            },
+        }
+    }
+
+    // Nothing has changed if there are no events
+    let snapshot = if events.is_empty() {
+        None
+    } else {
+        let mut take_snapshot = || match entity.take_snapshot() {
+            Some(snapshot) => match <E::Snapshot as AnyMessage>::encode(&snapshot) {
+                Ok(encoded) => Some(encoded),
+                Err(err) => {
+                    warn!(error = %err, "Couldn't encode snapshot");
+                    None
+                },
+            },
+            None => {
+                warn!("The entity hasn't provided a snapshot");
+                None
+            },
+        };
+        let mut context = SnapshotContext::new(sequence, &events, &mut take_snapshot);
+        if snapshot_policy.should_snapshot(&mut context) {
+            context.into_snapshot()
+        } else {
+            None
+        }
+    };
+
+    let side_effects = match result {
+        Ok(_) => side_effects,
+        Err(_) => vec![],
+    };
+
+    let action = match (result, forward) {
+        (Ok(Response::NoReply), Some(forward)) => {
+            EntityAction::Forward {
+                service_name: forward.service_name,
+                command_name: forward.command_name,
+                type_url: forward.type_url,
+                bytes: forward.bytes,
+            }
+        },
+        (result, _) => EntityAction::from_result(result),
+    };
+
+    EntityResponse {
+        action,
+        events,
+        snapshot,
+        side_effects,
+    }
 }
 
 pub struct EntityResponse {
     pub action: EntityAction,
-    pub events: Vec<(String, Bytes)>,
+    pub events: Vec<(String, Vec<u8>)>,
     pub snapshot: Option<(String, Vec<u8>)>,
-// side_effects: vec![], //TODO side effects
+    pub side_effects: Vec<SideEffect>,
 }
 
 
 // this is untyped entity handler interface for the server implementation
+#[async_trait]
 pub trait EventSourcedEntityHandler {
-    fn snapshot_received(&mut self, type_url: &str, bytes: Bytes);
-    fn command_received(&mut self, type_url: &str, bytes: Bytes, snapshot_sequence: i64) -> EntityResponse;
-    fn event_received(&mut self, type_url: &str, bytes: Bytes);
+    fn snapshot_received(&mut self, type_url: &str, bytes: &[u8]);
+    async fn command_received(&mut self, type_url: &str, bytes: &[u8], metadata: CommandMetadata<'_>, snapshot_policy: &dyn SnapshotPolicy) -> EntityResponse;
+    fn event_received(&mut self, type_url: &str, bytes: &[u8]);
+    fn on_init(&mut self, entity_id: &str);
+    fn on_recovered(&mut self, sequence: i64);
+    fn on_passivate(&mut self, passivation: Passivation);
+
+    fn message_types() -> MessageTypes where Self: Sized {
+        MessageTypes::default()
+    }
 }
 
-// This provides automatic implementation of EventSourcedEntityHandler for the server from the user's EventSourcedEntity implementation
+// This provides automatic implementation of EventSourcedEntityHandler for the server from the user's
+// EventSourcedEntity or AsyncEventSourcedEntity implementation
+#[async_trait]
 impl<T> EventSourcedEntityHandler for T
-    where T: EventSourcedEntity {
+    where T: AsyncEventSourcedEntity {
 
     #[inline]
-    fn snapshot_received(&mut self, type_url: &str, bytes: Bytes) {
-        self.snapshot_received(type_url, bytes)
+    fn snapshot_received(&mut self, type_url: &str, bytes: &[u8]) {
+        AsyncEventSourcedEntity::snapshot_received(self, type_url, bytes)
     }
 
-    #[inline]
-    fn command_received(&mut self, type_url: &str, bytes: Bytes, snapshot_sequence: i64) -> EntityResponse {
+    async fn command_received(&mut self, type_url: &str, bytes: &[u8], metadata: CommandMetadata<'_>, snapshot_policy: &dyn SnapshotPolicy) -> EntityResponse {
         // can't decode command here because a real type is needed that is an associated type
         // but associated types don't work with trait objects
-        self.command_received(type_url, bytes, snapshot_sequence)
+        AsyncEventSourcedEntity::command_received(self, type_url, bytes, metadata, snapshot_policy).await
     }
 
     #[inline]
-    fn event_received(&mut self, type_url: &str, bytes: Bytes) {
+    fn event_received(&mut self, type_url: &str, bytes: &[u8]) {
         // can't decode event here because a real type is needed that is an associated type
         // but associated types don't work with trait objects
-        self.event_received(type_url, bytes)
+        AsyncEventSourcedEntity::event_received(self, type_url, bytes)
+    }
+
+    #[inline]
+    fn on_init(&mut self, entity_id: &str) {
+        AsyncEventSourcedEntity::on_init(self, entity_id)
+    }
+
+    #[inline]
+    fn on_recovered(&mut self, sequence: i64) {
+        AsyncEventSourcedEntity::on_recovered(self, sequence)
+    }
+
+    #[inline]
+    fn on_passivate(&mut self, passivation: Passivation) {
+        AsyncEventSourcedEntity::on_passivate(self, passivation)
     }
-}
 
+    fn message_types() -> MessageTypes {
+        MessageTypes::of::<T::Command, T::Response>()
+    }
+}
@@ -0,0 +1,116 @@
+use bytes::Bytes;
+use std::sync::Arc;
+use tracing::{debug, warn};
+use crate::{AnyMessage, EntityAction, MessageTypes, Response};
+
+pub use crate::CommandError;
+
+// this is typed stateless function interface to be implemented by user
+// A single instance is shared by all the calls, so it can't hold any per-call state.
+pub trait StatelessFunction {
+
+    type Command : AnyMessage;
+    type Response : AnyMessage;
+
+    fn handle_command(&self, command: Self::Command) -> Result<Response<Self::Response>, CommandError>;
+
+    // Called once all the commands of a streamed in call are received
+    fn handle_streamed_in(&self, _commands: Vec<Self::Command>) -> Result<Response<Self::Response>, CommandError> {
+        Err(CommandError::Rejected("Streamed in calls aren't supported by the function".to_owned()))
+    }
+
+    // Replies with a stream of responses to a single command.
+    // By default it replies with the only response of the unary handler.
+    fn handle_streamed_out(&self, command: Self::Command) -> Vec<Result<Response<Self::Response>, CommandError>> {
+        vec![self.handle_command(command)]
+    }
+
+    // Called for every command of a streamed call.
+    // By default it's handled the same way as a streamed out call.
+    fn handle_streamed(&self, command: Self::Command) -> Vec<Result<Response<Self::Response>, CommandError>> {
+        self.handle_streamed_out(command)
+    }
+}
+
+// this is untyped stateless function handler interface for the server implementation
+pub trait StatelessFunctionHandler {
+    fn command_received(&self, command_name: &str, type_url: &str, bytes: Bytes) -> EntityAction;
+    fn streamed_in_received(&self, command_name: &str, commands: Vec<(String, Bytes)>) -> EntityAction;
+    fn streamed_out_received(&self, command_name: &str, type_url: &str, bytes: Bytes) -> Vec<EntityAction>;
+    fn streamed_received(&self, command_name: &str, type_url: &str, bytes: Bytes) -> Vec<EntityAction>;
+
+    fn message_types() -> MessageTypes where Self: Sized {
+        MessageTypes::default()
+    }
+}
+
+fn decode_command<C: AnyMessage>(command_name: &str, type_url: &str, bytes: Bytes) -> Result<C, EntityAction> {
+    debug!(type_url, "Handling received command");
+    <C as AnyMessage>::decode_command(command_name, type_url, bytes).map_err(|err| {
+        warn!(error = %err, "Couldn't decode command");
+        EntityAction::Failure(CommandError::Decode { type_url: type_url.to_owned() })
+    })
+}
+
+// This provides automatic implementation of StatelessFunctionHandler for the server from the user's StatelessFunction implementation
+impl<T> StatelessFunctionHandler for T
+    where T: StatelessFunction {
+
+    fn command_received(&self, command_name: &str, type_url: &str, bytes: Bytes) -> EntityAction {
+        match decode_command::<T::Command>(command_name, type_url, bytes) {
+            Ok(cmd) => EntityAction::from_result(self.handle_command(cmd)),
+            Err(failure) => failure,
+        }
+    }
+
+    fn streamed_in_received(&self, command_name: &str, commands: Vec<(String, Bytes)>) -> EntityAction {
+        let decoded: Result<Vec<T::Command>, EntityAction> = commands.into_iter()
+            .map(|(type_url, bytes)| decode_command::<T::Command>(command_name, &type_url, bytes))
+            .collect();
+        match decoded {
+            Ok(cmds) => EntityAction::from_result(self.handle_streamed_in(cmds)),
+            Err(failure) => failure,
+        }
+    }
+
+    fn streamed_out_received(&self, command_name: &str, type_url: &str, bytes: Bytes) -> Vec<EntityAction> {
+        match decode_command::<T::Command>(command_name, type_url, bytes) {
+            Ok(cmd) => self.handle_streamed_out(cmd).into_iter().map(EntityAction::from_result).collect(),
+            Err(failure) => vec![failure],
+        }
+    }
+
+    fn streamed_received(&self, command_name: &str, type_url: &str, bytes: Bytes) -> Vec<EntityAction> {
+        match decode_command::<T::Command>(command_name, type_url, bytes) {
+            Ok(cmd) => self.handle_streamed(cmd).into_iter().map(EntityAction::from_result).collect(),
+            Err(failure) => vec![failure],
+        }
+    }
+
+    fn message_types() -> MessageTypes {
+        MessageTypes::of::<T::Command, T::Response>()
+    }
+}
+
+pub struct StatelessFunctionDescriptor {
+    pub service_name: String,
+    pub message_types: MessageTypes,
+    handler: Arc<dyn StatelessFunctionHandler + Send + Sync>,
+}
+
+impl StatelessFunctionDescriptor {
+
+    pub(crate) fn new<H>(service_name: &str, handler: H) -> StatelessFunctionDescriptor
+        where H: StatelessFunctionHandler + Send + Sync + 'static
+    {
+        StatelessFunctionDescriptor {
+            service_name: service_name.to_owned(),
+            message_types: H::message_types(),
+            handler: Arc::new(handler),
+        }
+    }
+
+    pub fn handler(&self) -> Arc<dyn StatelessFunctionHandler + Send + Sync> {
+        self.handler.clone()
+    }
+}
@@ -1,10 +1,264 @@
+use bytes::Buf;
 
-use bytes::Bytes;
+// Async entities are implemented with it
+pub use async_trait::async_trait;
 
+// Used by the derived code, so the users don't need to depend on it
+#[doc(hidden)]
+pub use tracing;
+
+// Used by the code generated from the .proto services
+#[doc(hidden)]
+pub use bytes;
+
+// The payloads are decoded from whatever buffer they're in, e.g. borrowed from the protocol message,
+// and encoded into a buffer of the exact size which is then moved into the protocol message.
 pub trait AnyMessage: Sized {
-    fn decode(type_url: &str, bytes: Bytes) -> Option<Self>;
+    fn decode(type_url: &str, bytes: impl Buf) -> Result<Self, MessageError>;
+
+    // Decodes a command sent to the RPC method of that name.
+    // It lets the methods taking the same message be told apart, by default only the type URL matters.
+    fn decode_command(_command_name: &str, type_url: &str, bytes: impl Buf) -> Result<Self, MessageError> {
+        Self::decode(type_url, bytes)
+    }
+
+    fn encode(&self) -> Result<(String, Vec<u8>), MessageError>;
+
+    // The type URLs of all the messages it can hold, none if they aren't known
+    fn type_urls() -> Option<Vec<String>> {
+        None
+    }
+}
+
+// Why a message couldn't be decoded or encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageError {
+    // None of the messages it can hold has that type
+    UnknownType {
+        type_url: String,
+    },
+    // All the variants holding the message are bound to other commands
+    UnknownCommand {
+        command_name: String,
+        type_url: String,
+    },
+    // The bytes aren't a valid message of the type
+    Decode {
+        type_url: String,
+        error: String,
+    },
+    Encode {
+        type_url: String,
+        error: String,
+    },
+}
+
+impl MessageError {
+
+    pub fn unknown_type(type_url: &str) -> MessageError {
+        MessageError::UnknownType { type_url: type_url.to_owned() }
+    }
+
+    pub fn unknown_command(command_name: &str, type_url: &str) -> MessageError {
+        MessageError::UnknownCommand { command_name: command_name.to_owned(), type_url: type_url.to_owned() }
+    }
+
+    pub fn decode(type_url: &str, error: impl std::fmt::Display) -> MessageError {
+        MessageError::Decode { type_url: type_url.to_owned(), error: error.to_string() }
+    }
+
+    pub fn encode(type_url: &str, error: impl std::fmt::Display) -> MessageError {
+        MessageError::Encode { type_url: type_url.to_owned(), error: error.to_string() }
+    }
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::UnknownType { type_url } => write!(f, "Unknown message type {}", type_url),
+            MessageError::UnknownCommand { command_name, type_url } =>
+                write!(f, "Unknown command {} taking {}", command_name, type_url),
+            MessageError::Decode { type_url, error } => write!(f, "Couldn't decode {}: {}", type_url, error),
+            MessageError::Encode { type_url, error } => write!(f, "Couldn't encode {}: {}", type_url, error),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+// Names the protobuf type of a prost message so it's an `AnyMessage` on its own,
+// e.g. a snapshot or a reply which is always the same message. See `#[derive(MessageName)]`.
+pub trait MessageName {
+    // The protobuf package, e.g. `com.example.shoppingcart`
+    const PACKAGE: &'static str;
+    // The message name within the package, e.g. `Cart`
+    const NAME: &'static str;
+
+    fn type_url() -> String {
+        format!("type.googleapis.com/{}.{}", Self::PACKAGE, Self::NAME)
+    }
+}
+
+impl<T: MessageName + prost::Message + Default> AnyMessage for T {
+    fn decode(type_url: &str, bytes: impl Buf) -> Result<Self, MessageError> {
+        if type_url != T::type_url() {
+            return Err(MessageError::unknown_type(type_url));
+        }
+        <T as prost::Message>::decode(bytes).map_err(|err| MessageError::decode(type_url, err))
+    }
+
+    fn encode(&self) -> Result<(String, Vec<u8>), MessageError> {
+        let type_url = T::type_url();
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        match prost::Message::encode(self, &mut buf) {
+            Ok(()) => Ok((type_url, buf)),
+            Err(err) => Err(MessageError::encode(&type_url, err)),
+        }
+    }
+
+    fn type_urls() -> Option<Vec<String>> {
+        Some(vec![T::type_url()])
+    }
+}
+
+// The protobuf messages handled by an entity, they're checked against the entity service descriptor
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageTypes {
+    pub commands: Option<Vec<String>>,
+    pub responses: Option<Vec<String>>,
+}
+
+impl MessageTypes {
+
+    pub fn of<C: AnyMessage, R: AnyMessage>() -> MessageTypes {
+        MessageTypes {
+            commands: C::type_urls(),
+            responses: R::type_urls(),
+        }
+    }
+}
+
+pub enum Response<T> {
+    Reply(T),
+    EmptyReply,
+    // Used when the command has been forwarded to another service
+    NoReply,
+}
+
+impl<T> Response<T> {
+
+    // Maps the reply, e.g. into the variant of a reply enum
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Response<U> {
+        match self {
+            Response::Reply(reply) => Response::Reply(f(reply)),
+            Response::EmptyReply => Response::EmptyReply,
+            Response::NoReply => Response::NoReply,
+        }
+    }
+}
+
+// Why handling a command failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    // The entity rejected the command, e.g. it didn't pass validation.
+    // The client gets the message and the entity carries on.
+    Rejected(String),
+    // The command payload couldn't be decoded
+    Decode {
+        type_url: String,
+    },
+    // Something produced by the command handler couldn't be encoded, e.g. the reply or an event
+    Encode(String),
+    // The user function is broken, e.g. it replied and forwarded the same command
+    Internal(String),
+}
+
+impl CommandError {
+
+    // Only a rejection is the client's business, anything else means the entity is broken
+    pub fn is_rejection(&self) -> bool {
+        matches!(self, CommandError::Rejected(_))
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Rejected(msg) => write!(f, "{}", msg),
+            CommandError::Decode { type_url } => write!(f, "Server error: couldn't decode the command {}", type_url),
+            CommandError::Encode(what) => write!(f, "Server error: couldn't encode {}", what),
+            CommandError::Internal(msg) => write!(f, "Server error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+// Lets a command handler reject a command with `Err(msg.into())` or `?`
+impl From<String> for CommandError {
+    fn from(msg: String) -> Self {
+        CommandError::Rejected(msg)
+    }
+}
+
+impl From<&str> for CommandError {
+    fn from(msg: &str) -> Self {
+        CommandError::Rejected(msg.to_owned())
+    }
+}
+
+//TODO maybe rename to ClientAction but it will overlap with the prototype name?
+pub enum EntityAction {
+    Reply {
+        type_url: String,
+        bytes: Vec<u8>,
+    },
+    EmptyReply,
+    NoReply,
+    Forward {
+        service_name: String,
+        command_name: String,
+        type_url: String,
+        bytes: Vec<u8>,
+    },
+    Failure(CommandError),
+}
+
+pub struct SideEffect {
+    pub service_name: String,
+    pub command_name: String,
+    pub type_url: String,
+    pub bytes: Vec<u8>,
+    pub synchronous: bool,
+}
+
+impl EntityAction {
 
-    fn encode(&self) -> Option<(String, Vec<u8>)>;
+    // Encodes the result of a typed command handler into the untyped action sent back by the server
+    pub(crate) fn from_result<T: AnyMessage>(result: Result<Response<T>, CommandError>) -> EntityAction {
+        match result {
+            Ok(Response::Reply(resp)) => {
+                match <T as AnyMessage>::encode(&resp) {
+                    Ok((type_url, bytes)) => {
+                        EntityAction::Reply {
+                            type_url,
+                            bytes
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!(error = %err, "Couldn't encode the reply");
+                        EntityAction::Failure(CommandError::Encode("the reply".to_owned()))
+                    },
+                }
+            },
+            Ok(Response::EmptyReply) => EntityAction::EmptyReply,
+            Ok(Response::NoReply) => EntityAction::NoReply,
+            Err(error) => EntityAction::Failure(error),
+        }
+    }
 }
 
+pub mod registry;
 pub mod eventsourced;
+pub mod crdt;
+pub mod function;
@@ -0,0 +1,92 @@
+use crate::eventsourced::{EventSourcedEntityDescriptor, EventSourcedEntityHandler};
+use crate::crdt::{CrdtEntity, CrdtEntityDescriptor, CrdtEntityHandler};
+use crate::function::{StatelessFunctionDescriptor, StatelessFunctionHandler};
+use std::sync::Arc;
+
+pub struct EntityRegistry {
+    pub event_sourced_entities: Vec<EventSourcedEntityDescriptor>,
+    pub crdt_entities: Vec<CrdtEntityDescriptor>,
+    pub stateless_functions: Vec<StatelessFunctionDescriptor>,
+}
+
+impl Default for EntityRegistry {
+    fn default() -> Self {
+        EntityRegistry::new()
+    }
+}
+
+impl EntityRegistry {
+
+    pub fn new() -> EntityRegistry {
+        EntityRegistry {
+            event_sourced_entities: vec![],
+            crdt_entities: vec![],
+            stateless_functions: vec![],
+        }
+    }
+
+    // The factory gets the id of the entity to create.
+    // The registration can be configured further, e.g. with a snapshot policy.
+    pub fn register_event_sourced_entity<F, H>(&mut self, service_name: &str, persistence_id: &str, handler_factory: F) -> &mut EventSourcedEntityDescriptor
+        where F: Fn (&str) -> H + Send + Sync + 'static,
+              H: EventSourcedEntityHandler + Send + Sync + 'static
+    {
+        self.check_not_registered(service_name);
+
+        let create_entity_function = EventSourcedEntityDescriptor::new(service_name, persistence_id, handler_factory);
+        self.event_sourced_entities.push(create_entity_function);
+        self.event_sourced_entities.last_mut().unwrap()
+    }
+
+    pub fn register_crdt_entity<F, E>(&mut self, service_name: &str, entity_factory: F)
+        where F: Fn () -> E + Send + Sync + 'static,
+              E: CrdtEntity + Send + Sync + 'static,
+              E::State: Send + Sync + 'static,
+              E::Command: Send + Sync + 'static,
+    {
+        self.check_not_registered(service_name);
+
+        let create_entity_function = CrdtEntityDescriptor::new(service_name, entity_factory);
+        self.crdt_entities.push(create_entity_function);
+    }
+
+    // The function is shared by all the calls thus there's no factory
+    pub fn register_stateless_function<H>(&mut self, service_name: &str, function: H)
+        where H: StatelessFunctionHandler + Send + Sync + 'static
+    {
+        self.check_not_registered(service_name);
+
+        let function = StatelessFunctionDescriptor::new(service_name, function);
+        self.stateless_functions.push(function);
+    }
+
+    fn check_not_registered(&self, service_name: &str) {
+        let event_sourced = self.event_sourced_entities.iter().any(|v| v.service_name == service_name);
+        let crdt = self.crdt_entities.iter().any(|v| v.service_name == service_name);
+        let function = self.stateless_functions.iter().any(|v| v.service_name == service_name);
+        if event_sourced || crdt || function {
+            panic!("Entity {} already registered!", service_name);
+        }
+    }
+
+    pub fn event_sourced_entity(&self, entity_name: &str) -> Option<&EventSourcedEntityDescriptor> {
+        self.event_sourced_entities.iter()
+            .find(|v| v.service_name == entity_name)
+    }
+
+    pub fn create_event_sourced(&self, entity_name: &str, entity_id: &str) -> Option<Box<dyn EventSourcedEntityHandler + Send + Sync>> {
+        self.event_sourced_entity(entity_name).map(|v| v.create(entity_id))
+    }
+
+    pub fn create_crdt(&self, entity_name: &str) -> Option<Box<dyn CrdtEntityHandler + Send + Sync>> {
+        self.crdt_entities.iter()
+            .find(|v| v.service_name == entity_name)
+            .map(|v| v.create())
+    }
+
+    pub fn stateless_function(&self, service_name: &str) -> Option<Arc<dyn StatelessFunctionHandler + Send + Sync>> {
+        self.stateless_functions.iter()
+            .find(|v| v.service_name == service_name)
+            .map(|v| v.handler())
+    }
+}
@@ -0,0 +1,414 @@
+use protocols::protocol::cloudstate::{
+    Failure,
+    crdt::{
+        CrdtStreamIn, CrdtStreamOut, CrdtReply, CrdtStreamCancelledResponse, CrdtStreamedMessage,
+        crdt_stream_in, crdt_stream_out, crdt_state, crdt_delta, crdt_state_action,
+        crdt_server::Crdt,
+        GCounterState, PnCounterState, GSetState, OrSetState, LwwRegisterState, FlagState, VoteState,
+        GCounterDelta, PnCounterDelta, GSetDelta, OrSetDelta, LwwRegisterDelta, FlagDelta, VoteDelta,
+        CrdtDelete, CrdtClock, CrdtWriteConsistency,
+    },
+};
+use tonic::{Status, Streaming, Response, Request};
+use std::pin::Pin;
+use futures::Stream;
+use bytes::Bytes;
+use std::sync::Arc;
+use std::convert::TryFrom;
+use tracing::{debug, info_span, warn, field, Span};
+use cloudstate_core::registry::EntityRegistry;
+use cloudstate_core::crdt::{CrdtEntityHandler, CrdtState, CrdtDelta, CrdtStateAction};
+use cloudstate_core::crdt::CrdtStreamedMessage as EntityStreamedMessage;
+use crate::{client_action, entity_error, report_command_error};
+
+pub struct CrdtServerImpl(pub Arc<EntityRegistry>);
+
+#[tonic::async_trait]
+impl Crdt for CrdtServerImpl {
+
+    type handleStream = Pin<Box<dyn Stream<Item = Result<CrdtStreamOut, Status>> + Send + Sync + 'static>>;
+
+    async fn handle(&self, request: Request<Streaming<CrdtStreamIn>>) -> Result<Response<Self::handleStream>, Status> {
+        let mut stream = request.into_inner();
+
+        let registry = self.0.clone();
+
+        // The fields are recorded once the entity is initialized
+        let session_span = info_span!("crdt_session", service_name = field::Empty, entity_id = field::Empty);
+
+        let output = async_stream::try_stream! {
+            let mut session = CrdtSession::new(registry);
+
+            while let Some(in_msg) = stream.message().await? {
+
+                if let Some(known_msg) = in_msg.message {
+                    // none if protobuf version has unknown enum
+
+                    for out_msg in session_span.in_scope(|| session.handle_known_msg(known_msg)) {
+                        yield out_msg;
+                    }
+                } else {
+                    session_span.in_scope(|| warn!("Unknown message"));
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::handleStream))
+    }
+}
+
+enum CrdtSession {
+    New(Arc<EntityRegistry>),
+    Initialized {
+        entity_handler: Box<dyn CrdtEntityHandler + Send + Sync>,
+    },
+    // The entity couldn't be initialized, its commands can only fail
+    Failed(String),
+}
+
+impl CrdtSession {
+
+    fn new(registry: Arc<EntityRegistry>) -> CrdtSession {
+        CrdtSession::New(registry)
+    }
+
+    // Besides the reply to a command, a change of the state may send messages to the streamed commands
+    fn handle_known_msg(&mut self, known_msg: crdt_stream_in::Message) -> Vec<CrdtStreamOut> {
+        use crdt_stream_in::Message;
+
+        match known_msg {
+            Message::Init(init) => {
+                Span::current().record("service_name", init.service_name.as_str());
+                Span::current().record("entity_id", init.entity_id.as_str());
+                debug!("Initializing entity");
+                match &self {
+                    CrdtSession::New(entity_registry) => {
+                        let service_name = init.service_name;
+                        match entity_registry.create_crdt(&service_name) {
+                            Some(mut entity_handler) => {
+                                // Nothing is streamed yet
+                                let applied = match init.state.map(from_proto_state).transpose() {
+                                    Ok(Some(state)) => entity_handler.state_received(state).map(|_| ()),
+                                    Ok(None) => Ok(()),
+                                    Err(msg) => Err(msg),
+                                };
+                                if let Err(msg) = applied {
+                                    *self = CrdtSession::Failed(msg.clone());
+                                    return vec![failure(0, msg)];
+                                }
+                                *self = CrdtSession::Initialized {
+                                    entity_handler,
+                                };
+                                vec![]
+                            },
+                            None => {
+                                warn!(%service_name, "Unknown service name");
+                                let description = format!("Unknown service_name {}", service_name);
+                                *self = CrdtSession::Failed(description.clone());
+                                vec![failure(0, description)]
+                            },
+                        }
+                    }
+                    CrdtSession::Initialized { .. } | CrdtSession::Failed(_) => {
+                        warn!("Entity already initialized");
+                        vec![]
+                    },
+                }
+            },
+            Message::State(state) => {
+                match self {
+                    CrdtSession::Initialized { entity_handler } => {
+                        match from_proto_state(state) {
+                            Ok(state) => streamed_messages(entity_handler.state_received(state)),
+                            Err(msg) => {
+                                *self = CrdtSession::Failed(msg.clone());
+                                vec![failure(0, msg)]
+                            },
+                        }
+                    },
+                    _ => {
+                        warn!("Can't handle a state until the entity is initialized");
+                        vec![]
+                    },
+                }
+            },
+            Message::Changed(delta) => {
+                match self {
+                    CrdtSession::Initialized { entity_handler } => {
+                        match from_proto_delta(delta) {
+                            Ok(delta) => streamed_messages(entity_handler.delta_received(delta)),
+                            Err(msg) => {
+                                *self = CrdtSession::Failed(msg.clone());
+                                vec![failure(0, msg)]
+                            },
+                        }
+                    },
+                    _ => {
+                        warn!("Can't handle a delta until the entity is initialized");
+                        vec![]
+                    },
+                }
+            },
+            Message::Deleted(_) => {
+                match self {
+                    CrdtSession::Initialized { entity_handler } => {
+                        entity_handler.deleted().into_iter().map(streamed_message).collect()
+                    },
+                    _ => vec![],
+                }
+            },
+            Message::Command(cmd) => {
+                let span = info_span!("command", command_id = cmd.id, command_name = %cmd.name, streamed = cmd.streamed);
+                let _entered = span.enter();
+                match self {
+                    CrdtSession::Initialized { entity_handler } => {
+                        match cmd.payload {
+                            Some(payload_any) => {
+                                let type_url = payload_any.type_url;
+                                debug!(%type_url, "Handling command");
+                                let bytes = Bytes::from(payload_any.value);
+                                let entity_resp = entity_handler.command_received(cmd.id, &cmd.name, cmd.streamed, &type_url, bytes);
+
+                                if let Some(error) = entity_error(&entity_resp.action) {
+                                    report_command_error(&cmd.entity_id, &cmd.name, cmd.id, error);
+                                    return vec![failure(cmd.id, error.to_string())];
+                                }
+
+                                let reply = CrdtReply {
+                                    command_id: cmd.id,
+                                    client_action: client_action(entity_resp.action, cmd.id),
+                                    side_effects: vec![],
+                                    state_action: entity_resp.state_action.map(to_proto_state_action),
+                                    streamed: entity_resp.streamed,
+                                };
+                                let reply = CrdtStreamOut {
+                                    message: Some(crdt_stream_out::Message::Reply(reply)),
+                                };
+                                std::iter::once(reply)
+                                    .chain(entity_resp.streamed_messages.into_iter().map(streamed_message))
+                                    .collect()
+                            },
+                            None => {
+                                warn!("Command without payload");
+                                vec![failure(cmd.id, "Command without payload".to_owned())]
+                            },
+                        }
+                    },
+                    CrdtSession::Failed(description) => {
+                        warn!("Can't handle a command of the failed entity");
+                        vec![failure(cmd.id, description.clone())]
+                    },
+                    CrdtSession::New(_) => {
+                        warn!("Can't handle a command until the entity is initialized");
+                        vec![]
+                    },
+                }
+            },
+            Message::StreamCancelled(cancelled) => {
+                match self {
+                    CrdtSession::Initialized { entity_handler } => {
+                        debug!(command_id = cancelled.id, "Stream cancelled");
+                        let cancelled_resp = entity_handler.stream_cancelled(cancelled.id);
+                        let response = CrdtStreamCancelledResponse {
+                            command_id: cancelled.id,
+                            side_effects: vec![],
+                            state_action: cancelled_resp.state_action.map(to_proto_state_action),
+                        };
+                        let response = CrdtStreamOut {
+                            message: Some(crdt_stream_out::Message::StreamCancelledResponse(response)),
+                        };
+                        std::iter::once(response)
+                            .chain(cancelled_resp.streamed_messages.into_iter().map(streamed_message))
+                            .collect()
+                    },
+                    _ => {
+                        warn!("Can't cancel a stream until the entity is initialized");
+                        vec![]
+                    },
+                }
+            },
+        }
+    }
+}
+
+// The state received from the proxy is either applied and streamed or it fails the entity
+fn streamed_messages(result: Result<Vec<EntityStreamedMessage>, String>) -> Vec<CrdtStreamOut> {
+    match result {
+        Ok(messages) => messages.into_iter().map(streamed_message).collect(),
+        Err(msg) => vec![failure(0, msg)],
+    }
+}
+
+fn streamed_message(message: EntityStreamedMessage) -> CrdtStreamOut {
+    let streamed_message = CrdtStreamedMessage {
+        command_id: message.command_id,
+        client_action: client_action(message.action, message.command_id),
+        side_effects: vec![],
+        end_stream: message.end_stream,
+    };
+    CrdtStreamOut {
+        message: Some(crdt_stream_out::Message::StreamedMessage(streamed_message)),
+    }
+}
+
+fn failure(command_id: i64, description: String) -> CrdtStreamOut {
+    CrdtStreamOut {
+        message: Some(crdt_stream_out::Message::Failure(Failure {
+            command_id,
+            description,
+        })),
+    }
+}
+
+fn from_proto_any(any: ::prost_types::Any) -> (String, Bytes) {
+    (any.type_url, Bytes::from(any.value))
+}
+
+// The CRDT keeps its items, so they're copied into the message
+fn to_proto_any((type_url, bytes): (String, Bytes)) -> ::prost_types::Any {
+    ::prost_types::Any {
+        type_url,
+        value: bytes.to_vec(),
+    }
+}
+
+fn from_proto_state(state: protocols::protocol::cloudstate::crdt::CrdtState) -> Result<CrdtState, String> {
+    use crdt_state::State;
+
+    let state = match state.state.ok_or_else(|| "Unsupported CRDT state".to_owned())? {
+        State::Gcounter(GCounterState { value }) => CrdtState::GCounter { value },
+        State::Pncounter(PnCounterState { value }) => CrdtState::PNCounter { value },
+        State::Gset(GSetState { items }) => CrdtState::GSet {
+            items: items.into_iter().map(from_proto_any).collect(),
+        },
+        State::Orset(OrSetState { items }) => CrdtState::ORSet {
+            items: items.into_iter().map(from_proto_any).collect(),
+        },
+        State::Lwwregister(LwwRegisterState { value, .. }) => CrdtState::LWWRegister {
+            value: value.map(from_proto_any),
+        },
+        State::Flag(FlagState { value }) => CrdtState::Flag { value },
+        State::Vote(VoteState { votes_for, total_voters, self_vote }) => CrdtState::Vote {
+            votes_for,
+            total_voters,
+            self_vote,
+        },
+        State::Ormap(_) => {
+            //TODO ORMap
+            warn!("ORMap isn't supported yet");
+            return Err("Unsupported CRDT ORMap".to_owned());
+        },
+    };
+    Ok(state)
+}
+
+fn from_proto_delta(delta: protocols::protocol::cloudstate::crdt::CrdtDelta) -> Result<CrdtDelta, String> {
+    use crdt_delta::Delta;
+
+    let delta = match delta.delta.ok_or_else(|| "Unsupported CRDT delta".to_owned())? {
+        Delta::Gcounter(GCounterDelta { increment }) => CrdtDelta::GCounter { increment },
+        Delta::Pncounter(PnCounterDelta { change }) => CrdtDelta::PNCounter { change },
+        Delta::Gset(GSetDelta { added }) => CrdtDelta::GSet {
+            added: added.into_iter().map(from_proto_any).collect(),
+        },
+        Delta::Orset(OrSetDelta { cleared, removed, added }) => CrdtDelta::ORSet {
+            cleared,
+            removed: removed.into_iter().map(from_proto_any).collect(),
+            added: added.into_iter().map(from_proto_any).collect(),
+        },
+        Delta::Lwwregister(LwwRegisterDelta { value, .. }) => CrdtDelta::LWWRegister {
+            value: value.map(from_proto_any),
+        },
+        Delta::Flag(FlagDelta { value }) => CrdtDelta::Flag { value },
+        Delta::Vote(VoteDelta { self_vote, votes_for, total_voters }) => CrdtDelta::Vote {
+            self_vote,
+            votes_for: vote_count("votes_for", votes_for)?,
+            total_voters: vote_count("total_voters", total_voters)?,
+        },
+        Delta::Ormap(_) => {
+            //TODO ORMap
+            warn!("ORMap isn't supported yet");
+            return Err("Unsupported CRDT ORMap".to_owned());
+        },
+    };
+    Ok(delta)
+}
+
+// The vote delta counts are signed on the wire but can't be negative
+fn vote_count(field: &str, count: i32) -> Result<u32, String> {
+    u32::try_from(count).map_err(|_| format!("Invalid Vote delta, negative {}: {}", field, count))
+}
+
+fn to_proto_state(state: CrdtState) -> protocols::protocol::cloudstate::crdt::CrdtState {
+    use crdt_state::State;
+
+    let state = match state {
+        CrdtState::GCounter { value } => State::Gcounter(GCounterState { value }),
+        CrdtState::PNCounter { value } => State::Pncounter(PnCounterState { value }),
+        CrdtState::GSet { items } => State::Gset(GSetState {
+            items: items.into_iter().map(to_proto_any).collect(),
+        }),
+        CrdtState::ORSet { items } => State::Orset(OrSetState {
+            items: items.into_iter().map(to_proto_any).collect(),
+        }),
+        CrdtState::LWWRegister { value } => State::Lwwregister(LwwRegisterState {
+            value: value.map(to_proto_any),
+            clock: CrdtClock::Default as i32,
+            custom_clock_value: 0,
+        }),
+        CrdtState::Flag { value } => State::Flag(FlagState { value }),
+        CrdtState::Vote { votes_for, total_voters, self_vote } => State::Vote(VoteState {
+            votes_for,
+            total_voters,
+            self_vote,
+        }),
+    };
+    protocols::protocol::cloudstate::crdt::CrdtState {
+        state: Some(state),
+    }
+}
+
+fn to_proto_delta(delta: CrdtDelta) -> protocols::protocol::cloudstate::crdt::CrdtDelta {
+    use crdt_delta::Delta;
+
+    let delta = match delta {
+        CrdtDelta::GCounter { increment } => Delta::Gcounter(GCounterDelta { increment }),
+        CrdtDelta::PNCounter { change } => Delta::Pncounter(PnCounterDelta { change }),
+        CrdtDelta::GSet { added } => Delta::Gset(GSetDelta {
+            added: added.into_iter().map(to_proto_any).collect(),
+        }),
+        CrdtDelta::ORSet { cleared, removed, added } => Delta::Orset(OrSetDelta {
+            cleared,
+            removed: removed.into_iter().map(to_proto_any).collect(),
+            added: added.into_iter().map(to_proto_any).collect(),
+        }),
+        CrdtDelta::LWWRegister { value } => Delta::Lwwregister(LwwRegisterDelta {
+            value: value.map(to_proto_any),
+            clock: CrdtClock::Default as i32,
+            custom_clock_value: 0,
+        }),
+        CrdtDelta::Flag { value } => Delta::Flag(FlagDelta { value }),
+        CrdtDelta::Vote { self_vote, .. } => Delta::Vote(VoteDelta {
+            self_vote,
+            votes_for: 0,
+            total_voters: 0,
+        }),
+    };
+    protocols::protocol::cloudstate::crdt::CrdtDelta {
+        delta: Some(delta),
+    }
+}
+
+fn to_proto_state_action(state_action: CrdtStateAction) -> protocols::protocol::cloudstate::crdt::CrdtStateAction {
+    use crdt_state_action::Action;
+
+    let action = match state_action {
+        CrdtStateAction::Create(state) => Action::Create(to_proto_state(state)),
+        CrdtStateAction::Update(delta) => Action::Update(to_proto_delta(delta)),
+        CrdtStateAction::Delete => Action::Delete(CrdtDelete {}),
+    };
+    protocols::protocol::cloudstate::crdt::CrdtStateAction {
+        write_consistency: CrdtWriteConsistency::Local as i32,
+        action: Some(action),
+    }
+}
@@ -0,0 +1,120 @@
+use prost::Message;
+use prost_types::{FileDescriptorSet, ServiceDescriptorProto};
+use cloudstate_core::MessageTypes;
+use cloudstate_core::registry::EntityRegistry;
+
+// An entity may always reply with an empty message
+const EMPTY_TYPE: &str = "google.protobuf.Empty";
+
+// Why the registered entities don't match the descriptor set sent to the proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DescriptorError {
+    // The descriptor set isn't a valid FileDescriptorSet
+    Decode(String),
+    ServiceNotFound {
+        service_name: String,
+    },
+    // The entity command type can't hold the input of the method
+    UnhandledCommand {
+        service_name: String,
+        method: String,
+        message_type: String,
+    },
+    // The entity response type can't hold the output of the method
+    UnhandledReply {
+        service_name: String,
+        method: String,
+        message_type: String,
+    },
+}
+
+impl std::fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DescriptorError::Decode(msg) => write!(f, "Couldn't decode the descriptor set: {}", msg),
+            DescriptorError::ServiceNotFound { service_name } =>
+                write!(f, "Service {} not found in the descriptor set", service_name),
+            DescriptorError::UnhandledCommand { service_name, method, message_type } =>
+                write!(f, "The command type of {} doesn't handle {} of method {}", service_name, message_type, method),
+            DescriptorError::UnhandledReply { service_name, method, message_type } =>
+                write!(f, "The response type of {} doesn't handle {} of method {}", service_name, message_type, method),
+        }
+    }
+}
+
+impl std::error::Error for DescriptorError {}
+
+// Checks that the proxy will find every registered entity service in the descriptor set
+// and that the entities can handle all the messages of their services.
+// The skipped services aren't reported to the proxy so they don't need a descriptor.
+pub(crate) fn check_registry(descriptor_set: &[u8], registry: &EntityRegistry, skipped: &[String]) -> Result<(), DescriptorError> {
+    let descriptor_set = FileDescriptorSet::decode(descriptor_set)
+        .map_err(|err| DescriptorError::Decode(err.to_string()))?;
+
+    let event_sourced = registry.event_sourced_entities.iter().map(|v| (&v.service_name, &v.message_types));
+    let crdt = registry.crdt_entities.iter().map(|v| (&v.service_name, &v.message_types));
+    let functions = registry.stateless_functions.iter().map(|v| (&v.service_name, &v.message_types));
+
+    event_sourced.chain(crdt).chain(functions)
+        .filter(|(service_name, _)| !skipped.contains(service_name))
+        .try_for_each(|(service_name, message_types)| {
+            let service = find_service(&descriptor_set, service_name)
+                .ok_or_else(|| DescriptorError::ServiceNotFound { service_name: service_name.clone() })?;
+            check_service(service_name, service, message_types)
+        })
+}
+
+fn find_service<'a>(descriptor_set: &'a FileDescriptorSet, service_name: &str) -> Option<&'a ServiceDescriptorProto> {
+    descriptor_set.file.iter().find_map(|file| {
+        let package = file.package.as_deref().unwrap_or("");
+        file.service.iter().find(|service| {
+            let name = service.name.as_deref().unwrap_or("");
+            if package.is_empty() {
+                name == service_name
+            } else {
+                format!("{}.{}", package, name) == service_name
+            }
+        })
+    })
+}
+
+fn check_service(service_name: &str, service: &ServiceDescriptorProto, message_types: &MessageTypes) -> Result<(), DescriptorError> {
+    for method in &service.method {
+        let method_name = method.name.clone().unwrap_or_default();
+
+        let input_type = message_type(method.input_type.as_deref());
+        if !handles(&message_types.commands, input_type) {
+            return Err(DescriptorError::UnhandledCommand {
+                service_name: service_name.to_owned(),
+                method: method_name,
+                message_type: input_type.to_owned(),
+            });
+        }
+
+        let output_type = message_type(method.output_type.as_deref());
+        if output_type != EMPTY_TYPE && !handles(&message_types.responses, output_type) {
+            return Err(DescriptorError::UnhandledReply {
+                service_name: service_name.to_owned(),
+                method: method_name,
+                message_type: output_type.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Descriptors refer to the messages by their fully qualified names with a leading dot
+fn message_type(type_name: Option<&str>) -> &str {
+    let type_name = type_name.unwrap_or("");
+    type_name.strip_prefix('.').unwrap_or(type_name)
+}
+
+// Messages of unknown types can't be checked
+fn handles(type_urls: &Option<Vec<String>>, message_type: &str) -> bool {
+    match type_urls {
+        Some(type_urls) => type_urls.iter().any(|type_url| {
+            type_url.rsplit('/').next() == Some(message_type)
+        }),
+        None => true,
+    }
+}
@@ -0,0 +1,195 @@
+use protocols::protocol::cloudstate::{
+    Failure, client_action::Action,
+    function::{
+        FunctionCommand, FunctionReply, function_reply,
+        stateless_function_server::StatelessFunction,
+    },
+};
+use tonic::{Status, Streaming, Response, Request};
+use std::pin::Pin;
+use futures::Stream;
+use bytes::Bytes;
+use std::sync::Arc;
+use tracing::{debug, info_span, warn, Span};
+use cloudstate_core::EntityAction;
+use cloudstate_core::registry::EntityRegistry;
+use cloudstate_core::function::StatelessFunctionHandler;
+use crate::{client_action, entity_error, report_command_error};
+
+pub struct StatelessFunctionServerImpl(pub Arc<EntityRegistry>);
+
+type FunctionReplyStream = Pin<Box<dyn Stream<Item = Result<FunctionReply, Status>> + Send + Sync + 'static>>;
+
+#[tonic::async_trait]
+impl StatelessFunction for StatelessFunctionServerImpl {
+
+    async fn handle_unary(&self, request: Request<FunctionCommand>) -> Result<Response<FunctionReply>, Status> {
+        let cmd = request.into_inner();
+
+        let reply = command_span(&cmd).in_scope(|| {
+            debug!("Handling function command");
+            let service_name = cmd.service_name.clone();
+            match self.function(&service_name) {
+                Ok(function) => {
+                    let command_name = cmd.name.clone();
+                    let (type_url, bytes) = payload(cmd);
+                    function_reply(&service_name, &command_name, function.command_received(&command_name, &type_url, bytes))
+                },
+                Err(reply) => reply,
+            }
+        });
+        Ok(Response::new(reply))
+    }
+
+    async fn handle_streamed_in(&self, request: Request<Streaming<FunctionCommand>>) -> Result<Response<FunctionReply>, Status> {
+        let mut stream = request.into_inner();
+
+        let mut first_command: Option<(String, String, Span)> = None;
+        let mut commands = vec![];
+
+        while let Some(cmd) = stream.message().await? {
+            let span = match &first_command {
+                Some((_, _, span)) => span.clone(),
+                None => command_span(&cmd),
+            };
+            span.in_scope(|| debug!("Received function command"));
+            if first_command.is_none() {
+                first_command = Some((cmd.service_name.clone(), cmd.name.clone(), span));
+            }
+            commands.push(payload(cmd));
+        }
+
+        let reply = match first_command {
+            Some((service_name, command_name, span)) => span.in_scope(|| {
+                debug!(commands = commands.len(), "Handling function commands");
+                match self.function(&service_name) {
+                    Ok(function) => function_reply(&service_name, &command_name, function.streamed_in_received(&command_name, commands)),
+                    Err(reply) => reply,
+                }
+            }),
+            None => failure_reply("No commands received".to_owned()),
+        };
+        Ok(Response::new(reply))
+    }
+
+    type handleStreamedOutStream = FunctionReplyStream;
+
+    async fn handle_streamed_out(&self, request: Request<FunctionCommand>) -> Result<Response<Self::handleStreamedOutStream>, Status> {
+        let cmd = request.into_inner();
+
+        let replies = command_span(&cmd).in_scope(|| {
+            debug!("Handling function command");
+            let service_name = cmd.service_name.clone();
+            match self.function(&service_name) {
+                Ok(function) => {
+                    let command_name = cmd.name.clone();
+                    let (type_url, bytes) = payload(cmd);
+                    function.streamed_out_received(&command_name, &type_url, bytes).into_iter()
+                        .map(|action| function_reply(&service_name, &command_name, action))
+                        .collect()
+                },
+                Err(reply) => vec![reply],
+            }
+        });
+
+        let output = async_stream::try_stream! {
+            for reply in replies {
+                yield reply;
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::handleStreamedOutStream))
+    }
+
+    type handleStreamedStream = FunctionReplyStream;
+
+    async fn handle_streamed(&self, request: Request<Streaming<FunctionCommand>>) -> Result<Response<Self::handleStreamedStream>, Status> {
+        let mut stream = request.into_inner();
+
+        let registry = self.0.clone();
+
+        let output = async_stream::try_stream! {
+            while let Some(cmd) = stream.message().await? {
+                let replies = streamed_replies(&registry, cmd);
+
+                for reply in replies {
+                    yield reply;
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::handleStreamedStream))
+    }
+}
+
+impl StatelessFunctionServerImpl {
+
+    fn function(&self, service_name: &str) -> Result<Arc<dyn StatelessFunctionHandler + Send + Sync>, FunctionReply> {
+        self.0.stateless_function(service_name).ok_or_else(|| unknown_service(service_name))
+    }
+}
+
+fn streamed_replies(registry: &EntityRegistry, cmd: FunctionCommand) -> Vec<FunctionReply> {
+    let span = command_span(&cmd);
+    let _entered = span.enter();
+    debug!("Handling function command");
+    match registry.stateless_function(&cmd.service_name) {
+        Some(function) => {
+            let service_name = cmd.service_name.clone();
+            let command_name = cmd.name.clone();
+            let (type_url, bytes) = payload(cmd);
+            function.streamed_received(&command_name, &type_url, bytes).into_iter()
+                .map(|action| function_reply(&service_name, &command_name, action))
+                .collect()
+        },
+        None => vec![unknown_service(&cmd.service_name)],
+    }
+}
+
+fn command_span(cmd: &FunctionCommand) -> Span {
+    info_span!("function_command", service_name = %cmd.service_name, command_name = %cmd.name)
+}
+
+// A command without payload is passed on as an empty message of unknown type, so the function fails to decode it
+fn payload(cmd: FunctionCommand) -> (String, Bytes) {
+    match cmd.payload {
+        Some(payload_any) => (payload_any.type_url, Bytes::from(payload_any.value)),
+        None => {
+            warn!("Command without payload");
+            (String::new(), Bytes::new())
+        },
+    }
+}
+
+fn unknown_service(service_name: &str) -> FunctionReply {
+    warn!(%service_name, "Unknown service name");
+    failure_reply(format!("Unknown service_name {}", service_name))
+}
+
+fn failure_reply(description: String) -> FunctionReply {
+    FunctionReply {
+        response: Some(function_reply::Response::Failure(Failure {
+            command_id: 0,
+            description,
+        })),
+        side_effects: vec![],
+    }
+}
+
+// There's no stream to fail for a function, so any error is only logged and sent back to the client
+fn function_reply(service_name: &str, command_name: &str, action: EntityAction) -> FunctionReply {
+    if let Some(error) = entity_error(&action) {
+        report_command_error(service_name, command_name, 0, error);
+    }
+    let response = client_action(action, 0).and_then(|action| action.action).map(|action| {
+        match action {
+            Action::Reply(reply) => function_reply::Response::Reply(reply),
+            Action::Forward(forward) => function_reply::Response::Forward(forward),
+            Action::Failure(failure) => function_reply::Response::Failure(failure),
+        }
+    });
+    FunctionReply {
+        response,
+        side_effects: vec![],
+    }
+}
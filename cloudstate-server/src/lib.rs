@@ -2,20 +2,39 @@ use protocols::protocol::cloudstate::{eventsourced::{
     EventSourcedStreamIn, EventSourcedStreamOut, EventSourcedReply,
     event_sourced_stream_in, event_sourced_stream_out,
     event_sourced_server::EventSourced,
-}, entity_discovery_server::EntityDiscovery, ProxyInfo, EntitySpec, UserFunctionError, Entity, ServiceInfo, ClientAction,
-  client_action::Action
+}, entity_discovery_server::EntityDiscovery, ProxyInfo, EntitySpec, UserFunctionError, Entity, ClientAction,
+  client_action::Action, Forward, SideEffect, Command
 };
 use tonic::{Status, Streaming, Response, Request};
 use std::pin::Pin;
+use std::ops::{Deref, DerefMut};
+use sync_wrapper::SyncFuture;
 // use futures_core::Stream; // TODO: it caused compile issues
 use futures::Stream;
-use bytes::Bytes;
 use std::sync::Arc;
-use cloudstate_core::eventsourced::{EntityAction, EntityRegistry, EventSourcedEntityHandler, EntityResponse};
+use tracing::{debug, error, info, info_span, warn, field, Instrument, Span};
+use cloudstate_core::{CommandError, EntityAction};
+use cloudstate_core::SideEffect as EntitySideEffect;
+use cloudstate_core::registry::EntityRegistry;
+use cloudstate_core::eventsourced::{EventSourcedEntityHandler, EntityResponse, CommandMetadata, SnapshotPolicy, Passivation};
+
+mod crdt;
+mod descriptor;
+mod function;
+mod service;
+
+pub use crdt::CrdtServerImpl;
+pub use function::StatelessFunctionServerImpl;
+pub use descriptor::DescriptorError;
+pub use service::{CloudstateService, ServeError};
 
 pub struct EntityDiscoveryServerImpl {
     pub descriptor_set: Vec<u8>,
     pub entity_registry: Arc<EntityRegistry>,
+    pub service_name: String,
+    pub service_version: String,
+    // Served but not reported to the proxy
+    pub(crate) hidden_services: Vec<String>,
 }
 
 #[tonic::async_trait]
@@ -23,45 +42,94 @@ impl EntityDiscovery for EntityDiscoveryServerImpl {
 
     async fn discover(&self, request: Request<ProxyInfo>) -> Result<Response<EntitySpec>, Status> {
         let info = request.into_inner();
-        println!("---> EntityDiscovery.discover : request.message = {:?}", info);
+        info!(proxy_name = %info.proxy_name, proxy_version = %info.proxy_version, "Entity discovery requested");
 
         //TODO see Java impl for reference: io.cloudstate.javasupport.impl.EntityDiscoveryImpl#discover
 
-        //TODO check that request.into_inner().supported_entity_types contains entity_type
-        // if not log an error
-
-        let entities = self.entity_registry.event_sourced_entities.iter().map(|v| {
+        let event_sourced_entities = self.entity_registry.event_sourced_entities.iter().map(|v| {
             Entity {
-                entity_type: "cloudstate.eventsourced.EventSourced".to_owned(),
+                entity_type: EVENT_SOURCED_ENTITY_TYPE.to_owned(),
                 service_name: v.service_name.clone(),
                 persistence_id: v.persistence_id.clone(),
             }
-        }).collect();
+        });
+
+        let crdt_entities = self.entity_registry.crdt_entities.iter().map(|v| {
+            Entity {
+                entity_type: CRDT_ENTITY_TYPE.to_owned(),
+                service_name: v.service_name.clone(),
+                persistence_id: String::new(), // CRDT entities aren't persisted
+            }
+        });
+
+        let stateless_functions = self.entity_registry.stateless_functions.iter().map(|v| {
+            Entity {
+                entity_type: STATELESS_FUNCTION_ENTITY_TYPE.to_owned(),
+                service_name: v.service_name.clone(),
+                persistence_id: String::new(), // functions are stateless
+            }
+        });
+
+        let entities: Vec<Entity> = event_sourced_entities.chain(crdt_entities).chain(stateless_functions)
+            .filter(|entity| !self.hidden_services.contains(&entity.service_name))
+            .collect();
+
+        // The proxy can't do anything useful with a spec it doesn't support, so it's better to fail the discovery
+        if let Err(message) = check_proxy_info(&info, &entities) {
+            error!(proxy_name = %info.proxy_name, proxy_version = %info.proxy_version, error = %message, "Incompatible proxy");
+            return Err(Status::failed_precondition(message));
+        }
 
         let reply = EntitySpec {
             proto: self.descriptor_set.clone(),
             entities,
-            service_info: Some(
-                ServiceInfo {
-                    service_name: "shopping-cart".to_owned(), //TODO should be provided from the service builder / descriptor
-                    service_version: "0.1".to_owned(),
-                    service_runtime: "rustc 1.43.0 (4fb7144ed 2020-04-20)".to_owned(), //TODO use rust version
-                    support_library_name: "cloudstate".to_owned(),
-                    support_library_version: "0.1".to_owned(), //TODO use version of the library
-                }
-            ),
+            service_info: Some(service::service_info(&self.service_name, &self.service_version)),
         };
 
         Ok(Response::new(reply))
     }
 
     async fn report_error(&self, request: Request<UserFunctionError>) -> Result<Response<()>, Status> {
-        println!("---> EntityDiscovery.report_error: error = {:?}", request.into_inner());
+        error!(error = %request.into_inner().message, "Error reported by the proxy");
 
         Ok(Response::new(()))
     }
 }
 
+// The version of the Cloudstate protocol implemented by this library
+pub const PROTOCOL_MAJOR_VERSION: i32 = 0;
+pub const PROTOCOL_MINOR_VERSION: i32 = 1;
+
+const EVENT_SOURCED_ENTITY_TYPE: &str = "cloudstate.eventsourced.EventSourced";
+const CRDT_ENTITY_TYPE: &str = "cloudstate.crdt.Crdt";
+const STATELESS_FUNCTION_ENTITY_TYPE: &str = "cloudstate.function.StatelessFunction";
+
+fn check_proxy_info(info: &ProxyInfo, entities: &[Entity]) -> Result<(), String> {
+    // A proxy speaking a different major version or an older minor one doesn't understand everything we send
+    let protocol_supported = info.protocol_major_version == PROTOCOL_MAJOR_VERSION
+        && info.protocol_minor_version >= PROTOCOL_MINOR_VERSION;
+    if !protocol_supported {
+        return Err(format!(
+            "Proxy {} {} implements protocol version {}.{} but this library requires {}.{} or a later minor version",
+            info.proxy_name, info.proxy_version, info.protocol_major_version, info.protocol_minor_version,
+            PROTOCOL_MAJOR_VERSION, PROTOCOL_MINOR_VERSION,
+        ));
+    }
+
+    let unsupported: Vec<String> = entities.iter()
+        .filter(|entity| !info.supported_entity_types.contains(&entity.entity_type))
+        .map(|entity| format!("{} ({})", entity.service_name, entity.entity_type))
+        .collect();
+    if !unsupported.is_empty() {
+        return Err(format!(
+            "Proxy {} {} doesn't support the entity types of {}, supported entity types are [{}]",
+            info.proxy_name, info.proxy_version, unsupported.join(", "), info.supported_entity_types.join(", "),
+        ));
+    }
+
+    Ok(())
+}
+
 pub struct EventSourcedServerImpl(pub Arc<EntityRegistry>);
 
 #[tonic::async_trait]
@@ -79,6 +147,9 @@ impl EventSourced for EventSourcedServerImpl {
 
         let registry = self.0.clone();
 
+        // The fields are recorded once the entity is initialized
+        let session_span = info_span!("event_sourced_session", service_name = field::Empty, entity_id = field::Empty);
+
         let output = async_stream::try_stream! {
             // while let Some(message) = stream.next().await {
             // got from the examples but it doesn't work, perhaps in previous version of tonic before 0.2.0
@@ -86,21 +157,24 @@ impl EventSourced for EventSourcedServerImpl {
 
             let mut session = EventSourcedSession::new(registry);
 
-            session.session_started();
+            session_span.in_scope(|| session.session_started());
 
-            while let Some(in_msg) = stream.message().await? { // msg: EventSourcedStreamIn
+            while let Some(in_msg) = next_message(&mut stream, &mut session, &session_span).await? { // msg: EventSourcedStreamIn
 
                 if let Some(known_msg) = in_msg.message {
                     // none if protobuf version has unknown enum
 
-                    if let Some(out_msg) = session.handle_known_msg(known_msg) {
+                    // Entities handle commands asynchronously, their futures are only Send
+                    // but the stream has to be Sync
+                    let handled = session.handle_known_msg(known_msg).instrument(session_span.clone());
+                    if let Some(out_msg) = SyncFuture::new(handled).await {
                         yield out_msg;
                     }
                 } else {
-                    println!("unknown message")
+                    session_span.in_scope(|| warn!("Unknown message"));
                 }
             }
-            session.session_finished(); // might not be called
+            session_span.in_scope(|| session.session_finished());
         };
 
         Ok(Response::new(Box::pin(output) as Self::handleStream))
@@ -108,12 +182,67 @@ impl EventSourced for EventSourcedServerImpl {
     }
 }
 
+// The entity is passivated once the error of the stream is returned
+async fn next_message(stream: &mut Streaming<EventSourcedStreamIn>, session: &mut EventSourcedSession, session_span: &Span) -> Result<Option<EventSourcedStreamIn>, Status> {
+    let result = stream.message().await;
+    if let Err(status) = &result {
+        session_span.in_scope(|| session.session_failed(status.message()));
+    }
+    result
+}
+
+// Passivates the entity when it's dropped, so the hook runs however the session ends,
+// even when the stream is dropped without being closed, e.g. the proxy disconnected abruptly.
+struct EntityGuard {
+    entity_handler: Box<dyn EventSourcedEntityHandler + Send + Sync>,
+    passivation: Passivation,
+}
+
+impl EntityGuard {
+
+    fn new(entity_handler: Box<dyn EventSourcedEntityHandler + Send + Sync>) -> EntityGuard {
+        EntityGuard {
+            entity_handler,
+            passivation: Passivation::Dropped,
+        }
+    }
+}
+
+impl Deref for EntityGuard {
+    type Target = dyn EventSourcedEntityHandler + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.entity_handler
+    }
+}
+
+impl DerefMut for EntityGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.entity_handler
+    }
+}
+
+impl Drop for EntityGuard {
+    fn drop(&mut self) {
+        let passivation = std::mem::replace(&mut self.passivation, Passivation::Dropped);
+        debug!(?passivation, "Passivating entity");
+        self.entity_handler.on_passivate(passivation);
+    }
+}
+
 enum EventSourcedSession {
     New(Arc<EntityRegistry>),
     Initialized {
-        entity_handler: Box<dyn EventSourcedEntityHandler + Send + Sync>,
-        snapshot_sequence: i64,
+        entity_id: String,
+        entity_handler: EntityGuard,
+        // The sequence number of the last event applied to the entity
+        sequence: i64,
+        // The events are replayed until the first command
+        recovered: bool,
+        snapshot_policy: Arc<dyn SnapshotPolicy>,
     },
+    // The entity state can't be trusted anymore, e.g. an event has been missed
+    Failed(String),
 }
 
 impl EventSourcedSession {
@@ -123,190 +252,302 @@ impl EventSourcedSession {
     }
 
     fn session_started(&mut self) {
-        println!("starting session");
+        debug!("Session started");
     }
 
+    // The entity is passivated when the session is dropped, see EntityGuard
     fn session_finished(&mut self) {
-        //TODO it's not called if the session is not closed properly on the client.
-        // It will lead to the resource leak. How to prevent it?
-        println!("session finished");
+        debug!("Session finished");
+        self.passivate(Passivation::Closed);
+    }
+
+    fn session_failed(&mut self, description: &str) {
+        warn!(%description, "Session failed");
+        self.passivate(Passivation::Failed(description.to_owned()));
+    }
+
+    fn passivate(&mut self, passivation: Passivation) {
+        if let EventSourcedSession::Initialized { entity_handler, .. } = self {
+            entity_handler.passivation = passivation;
+        }
     }
 
-    fn handle_known_msg(&mut self, known_msg: event_sourced_stream_in::Message) -> Option<EventSourcedStreamOut> {
+    async fn handle_known_msg(&mut self, known_msg: event_sourced_stream_in::Message) -> Option<EventSourcedStreamOut> {
         use event_sourced_stream_in::Message;
 
         match known_msg {
             Message::Init(init) => {
-                println!("init service: {} entity_id: {}", init.service_name, init.entity_id);
+                Span::current().record("service_name", init.service_name.as_str());
+                Span::current().record("entity_id", init.entity_id.as_str());
+                debug!("Initializing entity");
                 match &self {
                     EventSourcedSession::New(entity_registry) => {
                         let service_name = init.service_name;
-                        match entity_registry.create(&service_name) {
-                            Some(mut entity_handler) => {
-                                let snapshot_sequence: i64;
+                        match entity_registry.event_sourced_entity(&service_name) {
+                            Some(entity) => {
+                                let mut entity_handler = EntityGuard::new(entity.create(&init.entity_id));
+                                let sequence: i64;
                                 if let Some(snapshot) = init.snapshot {
-                                    snapshot_sequence = snapshot.snapshot_sequence;
-                                    println!("snapshot: seq_id = {}", snapshot_sequence);
+                                    sequence = snapshot.snapshot_sequence;
+                                    debug!(sequence, "Initial snapshot provided");
 
                                     if let Some(snapshot_any) = snapshot.snapshot {
-                                        let type_url = snapshot_any.type_url;
-                                        let bytes = Bytes::from(snapshot_any.value);
-                                        entity_handler.snapshot_received(&type_url, bytes);
+                                        entity_handler.snapshot_received(&snapshot_any.type_url, &snapshot_any.value);
                                     }
                                 } else {
-                                    snapshot_sequence = 0;
-                                    println!("No initial snapshot provided!");
+                                    sequence = 0;
+                                    debug!("No initial snapshot provided");
                                 }
+                                entity_handler.on_init(&init.entity_id);
                                 *self = EventSourcedSession::Initialized {
+                                    entity_id: init.entity_id,
                                     entity_handler,
-                                    snapshot_sequence,
+                                    sequence,
+                                    recovered: false,
+                                    snapshot_policy: entity.snapshot_policy(),
                                 };
                             },
                             None => {
-                                println!("Unknown service_name {}", service_name);
+                                warn!(%service_name, "Unknown service name");
                             },
                         }
                     }
-                    EventSourcedSession::Initialized { .. } => {
-                        println!("Entity already initialized!");
+                    _ => {
+                        warn!("Entity already initialized");
                     },
                 };
                 None
             },
             Message::Event(evt) => {
                 match self {
-                    EventSourcedSession::Initialized { entity_handler, .. } => {
+                    EventSourcedSession::Initialized { entity_handler, sequence, .. } => {
+                        // A missed or repeated event would silently corrupt the entity state
+                        if evt.sequence != *sequence + 1 {
+                            let description = format!("Received event {} while expecting event {}", evt.sequence, *sequence + 1);
+                            error!(sequence = evt.sequence, expected_sequence = *sequence + 1, "Unexpected event sequence");
+                            self.passivate(Passivation::Failed(description.clone()));
+                            *self = EventSourcedSession::Failed(description.clone());
+                            return Some(stream_failure(0, description));
+                        }
+                        *sequence = evt.sequence;
                         if let Some(event_any) = evt.payload {
-                            let type_url = event_any.type_url;
-                            println!("Handling event: {}", &type_url);
-                            let bytes = Bytes::from(event_any.value);
-                            //TODO maybe verify evt.sequence to make sure no events where skipped?
-                            //TODO update snapshot_sequence!
-                            entity_handler.event_received(&type_url, bytes);
+                            debug!(type_url = %event_any.type_url, sequence = evt.sequence, "Handling event");
+                            entity_handler.event_received(&event_any.type_url, &event_any.value);
                         }
                     },
-                    _ => {
-                        println!("Can't handle a event until the entity is initialized!");
+                    EventSourcedSession::Failed(_) => {
+                        warn!(sequence = evt.sequence, "Ignoring an event of the failed entity");
+                    },
+                    EventSourcedSession::New(_) => {
+                        warn!("Can't handle an event until the entity is initialized");
                     },
                 }
                 None
             },
             Message::Command(cmd) => {
-                match self {
-                    EventSourcedSession::Initialized { entity_handler, ref mut snapshot_sequence } => {
-                        match cmd.payload {
-                            Some(payload_any) => {
-                                let type_url = payload_any.type_url;
-                                println!("Handling command: {}", type_url);
-                                let bytes = Bytes::from(payload_any.value);
-                                let entity_resp: EntityResponse = entity_handler.command_received(&type_url, bytes, *snapshot_sequence);
-
-                                let client_action = match entity_resp.action {
-                                    EntityAction::Reply { type_url, bytes } => {
-                                        ClientAction { // TODO maybe extract client action local factory?
-                                            action: Some(
-                                                Action::Reply(
-                                                    protocols::protocol::cloudstate::Reply {
-                                                        payload: Some(
-                                                            ::prost_types::Any {
-                                                                type_url,
-                                                                value: bytes
-                                                            }
-                                                        )
-                                                    }
-                                                )
-                                            )
-                                        }
-                                    },
-                                    EntityAction::EmptyReply => {
-                                        // TODO construct only once
-                                        let mut buf = vec![];
-                                        use ::prost::Message;
-                                        ().encode(&mut buf).unwrap();
-                                        let type_url = "type.googleapis.com/google.protobuf.Empty".to_owned();
-
-                                        ClientAction { // TODO maybe extract client action local factory?
-                                            action: Some(
-                                                Action::Reply(
-                                                    protocols::protocol::cloudstate::Reply {
-                                                        payload: Some(
-                                                            ::prost_types::Any {
-                                                                type_url,
-                                                                value: buf
-                                                            }
-                                                        )
-                                                    }
-                                                )
-                                            )
-                                        }
-                                    },
-                                    EntityAction::Failure { msg } => {
-                                        ClientAction { // TODO maybe extract client action local factory?
-                                            action: Some(
-                                                Action::Failure(
-                                                    protocols::protocol::cloudstate::Failure {
-                                                        command_id: cmd.id,
-                                                        description: msg
-                                                    }
-                                                )
-                                            )
-                                        }
-                                    },
-                                };
-
-                                let events: Vec<_> = entity_resp.events.into_iter().map(
-                                    |(tp, bs)| {
-                                        //TODO extract method to construct Any?
-                                        ::prost_types::Any {
-                                            type_url: tp,
-                                            value: bs.to_vec() //TODO maybe get rid of the bytes type here?
-                                        }
-                                    }
-                                ).collect();
-
-                                // Increase snapshot_sequence by the number of emitted events
-                                *snapshot_sequence += events.len() as i64;
-
-                                let snapshot = entity_resp.snapshot.map(|(type_url, bytes)| {
-                                    ::prost_types::Any {
-                                        type_url,
-                                        value: bytes,
-                                    }
-                                });
+                let span = info_span!("command", command_id = cmd.id, command_name = %cmd.name);
+                self.handle_command(cmd).instrument(span).await
+            },
+        }
 
-                                use event_sourced_stream_out::Message::*;
+    }
 
-                                let reply = EventSourcedReply {
-                                    command_id: cmd.id,
-                                    client_action: Some(client_action),
-                                    side_effects: vec![], //TODO side effects
-                                    events,
-                                    snapshot,
-                                };
-                                let out_msg = EventSourcedStreamOut {
-                                    message: Some(Reply(reply)),
-                                };
-                                Some(out_msg)
+    async fn handle_command(&mut self, cmd: Command) -> Option<EventSourcedStreamOut> {
+        if let EventSourcedSession::Initialized { entity_handler, sequence, recovered, .. } = self {
+            if !*recovered {
+                debug!(sequence = *sequence, "Entity recovered");
+                *recovered = true;
+                entity_handler.on_recovered(*sequence);
+            }
+        }
 
-                            },
-                            None => {
-                                println!("Command without payload!");
-                                // let out_msg = EventSourcedStreamOut {
-                                //     message: Some(Failure(reply)),
-                                // };
-                                // Some(out_msg)
-                                None
-                            },
+        match self {
+            // The event sourced protocol has no streamed messages, only CRDT entities can stream.
+            // The command is rejected, the entity isn't broken and carries on.
+            EventSourcedSession::Initialized { .. } if cmd.streamed => {
+                warn!("Streamed commands aren't supported by event sourced entities");
+                let error = CommandError::Rejected("Streamed commands aren't supported by event sourced entities".to_owned());
+                let reply = EventSourcedReply {
+                    command_id: cmd.id,
+                    client_action: client_action(EntityAction::Failure(error), cmd.id),
+                    side_effects: vec![],
+                    events: vec![],
+                    snapshot: None,
+                };
+                Some(EventSourcedStreamOut {
+                    message: Some(event_sourced_stream_out::Message::Reply(reply)),
+                })
+            },
+            EventSourcedSession::Initialized { entity_id, entity_handler, ref mut sequence, snapshot_policy, .. } => {
+                match cmd.payload {
+                    Some(payload_any) => {
+                        debug!(type_url = %payload_any.type_url, "Handling command");
+                        let metadata = CommandMetadata {
+                            entity_id,
+                            command_id: cmd.id,
+                            command_name: &cmd.name,
+                            sequence: *sequence,
+                        };
+                        let entity_resp: EntityResponse = entity_handler.command_received(&payload_any.type_url, &payload_any.value, metadata, &**snapshot_policy).await;
+
+                        use event_sourced_stream_out::Message::*;
+
+                        if let Some(error) = entity_error(&entity_resp.action) {
+                            report_command_error(&cmd.entity_id, &cmd.name, cmd.id, error);
+                            return Some(stream_failure(cmd.id, error.to_string()));
                         }
 
+                        let client_action = client_action(entity_resp.action, cmd.id);
+
+                        // The encoded payloads are moved as they are, nothing is copied
+                        let events: Vec<_> = entity_resp.events.into_iter().map(
+                            |(type_url, value)| ::prost_types::Any { type_url, value }
+                        ).collect();
+
+                        // The emitted events get the following sequence numbers
+                        *sequence += events.len() as i64;
+
+                        let snapshot = entity_resp.snapshot.map(|(type_url, bytes)| {
+                            ::prost_types::Any {
+                                type_url,
+                                value: bytes,
+                            }
+                        });
+
+                        let reply = EventSourcedReply {
+                            command_id: cmd.id,
+                            client_action,
+                            side_effects: side_effects(entity_resp.side_effects),
+                            events,
+                            snapshot,
+                        };
+                        let out_msg = EventSourcedStreamOut {
+                            message: Some(Reply(reply)),
+                        };
+                        Some(out_msg)
+
                     },
-                    _ => {
-                        println!("Can't handle a command until the entity is initialized!");
+                    None => {
+                        warn!("Command without payload");
+                        // let out_msg = EventSourcedStreamOut {
+                        //     message: Some(Failure(reply)),
+                        // };
+                        // Some(out_msg)
                         None
                     },
                 }
+
+            },
+            EventSourcedSession::Failed(description) => {
+                warn!("Can't handle a command of the failed entity");
+                Some(stream_failure(cmd.id, description.clone()))
+            },
+            EventSourcedSession::New(_) => {
+                warn!("Can't handle a command until the entity is initialized");
+                None
             },
         }
+    }
+}
+
+// Fails the whole entity rather than a single command
+fn stream_failure(command_id: i64, description: String) -> EventSourcedStreamOut {
+    EventSourcedStreamOut {
+        message: Some(event_sourced_stream_out::Message::Failure(protocols::protocol::cloudstate::Failure {
+            command_id,
+            description,
+        })),
+    }
+}
+
+// Converts an action returned by an entity into the protocol's client action.
+// There's no client action when the entity doesn't reply.
+pub(crate) fn client_action(action: EntityAction, command_id: i64) -> Option<ClientAction> {
+    let action = match action {
+        EntityAction::Reply { type_url, bytes } => {
+            Action::Reply(
+                protocols::protocol::cloudstate::Reply {
+                    payload: Some(
+                        ::prost_types::Any {
+                            type_url,
+                            value: bytes
+                        }
+                    )
+                }
+            )
+        },
+        EntityAction::EmptyReply => {
+            // google.protobuf.Empty is encoded as no bytes at all
+            let buf = vec![];
+            let type_url = "type.googleapis.com/google.protobuf.Empty".to_owned();
+
+            Action::Reply(
+                protocols::protocol::cloudstate::Reply {
+                    payload: Some(
+                        ::prost_types::Any {
+                            type_url,
+                            value: buf
+                        }
+                    )
+                }
+            )
+        },
+        EntityAction::NoReply => return None,
+        EntityAction::Forward { service_name, command_name, type_url, bytes } => {
+            Action::Forward(
+                Forward {
+                    service_name,
+                    command_name,
+                    payload: Some(
+                        ::prost_types::Any {
+                            type_url,
+                            value: bytes
+                        }
+                    )
+                }
+            )
+        },
+        EntityAction::Failure(error) => {
+            Action::Failure(
+                protocols::protocol::cloudstate::Failure {
+                    command_id,
+                    description: error.to_string()
+                }
+            )
+        },
+    };
+    Some(ClientAction {
+        action: Some(action)
+    })
+}
 
+// A failure other than a rejection means that the entity is broken, so it fails the whole stream
+// and the proxy restarts the entity instead of only failing the command
+pub(crate) fn entity_error(action: &EntityAction) -> Option<&CommandError> {
+    match action {
+        EntityAction::Failure(error) if !error.is_rejection() => Some(error),
+        _ => None,
     }
 }
+
+// Logged the same way as the errors reported by the proxy through report_error.
+// The target is the entity id or the service name of a function.
+pub(crate) fn report_command_error(target: &str, command_name: &str, command_id: i64, error: &CommandError) {
+    error!(%target, command_name, command_id, %error, "Command failed");
+}
+
+pub(crate) fn side_effects(side_effects: Vec<EntitySideEffect>) -> Vec<SideEffect> {
+    side_effects.into_iter().map(|effect| {
+        SideEffect {
+            service_name: effect.service_name,
+            command_name: effect.command_name,
+            payload: Some(
+                ::prost_types::Any {
+                    type_url: effect.type_url,
+                    value: effect.bytes
+                }
+            ),
+            synchronous: effect.synchronous,
+        }
+    }).collect()
+}
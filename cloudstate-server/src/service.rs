@@ -0,0 +1,158 @@
+use protocols::protocol::cloudstate::{
+    ServiceInfo,
+    entity_discovery_server::EntityDiscoveryServer,
+    eventsourced::event_sourced_server::EventSourcedServer,
+    crdt::crdt_server::CrdtServer,
+    function::stateless_function_server::StatelessFunctionServer,
+};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Service, StdError};
+use tonic::transport::{Body, Server};
+use tonic::transport::server::Router;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use cloudstate_core::registry::EntityRegistry;
+use crate::descriptor::{self, DescriptorError};
+use crate::{EntityDiscoveryServerImpl, EventSourcedServerImpl, CrdtServerImpl, StatelessFunctionServerImpl};
+
+// Reported to the proxy as the support library
+const SUPPORT_LIBRARY_NAME: &str = "cloudstate";
+const SUPPORT_LIBRARY_VERSION: &str = env!("CARGO_PKG_VERSION");
+// Captured by the build script
+const SERVICE_RUNTIME: &str = env!("RUSTC_VERSION");
+
+// Puts together everything the proxy needs to know about the user function
+// and serves discovery along with all the entity services.
+pub struct CloudstateService {
+    service_name: String,
+    service_version: String,
+    descriptor_set: Vec<u8>,
+    registry: EntityRegistry,
+    hidden_services: Vec<String>,
+}
+
+impl CloudstateService {
+
+    pub fn new(service_name: &str, service_version: &str) -> CloudstateService {
+        CloudstateService {
+            service_name: service_name.to_owned(),
+            service_version: service_version.to_owned(),
+            descriptor_set: vec![],
+            registry: EntityRegistry::new(),
+            hidden_services: vec![],
+        }
+    }
+
+    // The serialized FileDescriptorSet of the entity services, it's sent to the proxy on discovery
+    pub fn descriptor_set(mut self, descriptor_set: &[u8]) -> CloudstateService {
+        self.descriptor_set = descriptor_set.to_vec();
+        self
+    }
+
+    pub fn registry(mut self, registry: EntityRegistry) -> CloudstateService {
+        self.registry = registry;
+        self
+    }
+
+    // The entity of the service is served but the proxy isn't told about it, it's a test fixture.
+    // Such a service doesn't have to be in the descriptor set.
+    #[cfg(feature = "test-fixtures")]
+    pub fn hidden_service(mut self, service_name: &str) -> CloudstateService {
+        self.hidden_services.push(service_name.to_owned());
+        self
+    }
+
+    // Checks that the registered entities match their services in the descriptor set
+    pub fn validate(&self) -> Result<(), DescriptorError> {
+        descriptor::check_registry(&self.descriptor_set, &self.registry, &self.hidden_services)
+    }
+
+    pub fn service_info(&self) -> ServiceInfo {
+        service_info(&self.service_name, &self.service_version)
+    }
+
+    // The type of the other services can't be named since tonic keeps its router combinator private.
+    // The service is validated first, the proxy would reject an invalid one with a much less precise error.
+    #[allow(clippy::type_complexity)]
+    pub fn router(self) -> Result<Router<
+        StatelessFunctionServer<StatelessFunctionServerImpl>,
+        impl Service<
+            http::Request<Body>,
+            Response = http::Response<BoxBody>,
+            Error = StdError,
+            Future = impl Future<Output = Result<http::Response<BoxBody>, StdError>> + Send + 'static,
+        > + Clone + Send + 'static,
+    >, DescriptorError> {
+        self.validate()?;
+
+        let entity_registry = Arc::new(self.registry);
+
+        let discovery = EntityDiscoveryServerImpl {
+            descriptor_set: self.descriptor_set,
+            entity_registry: entity_registry.clone(),
+            service_name: self.service_name,
+            service_version: self.service_version,
+            hidden_services: self.hidden_services,
+        };
+
+        let router = Server::builder()
+            .add_service(EntityDiscoveryServer::new(discovery))
+            .add_service(EventSourcedServer::new(EventSourcedServerImpl(entity_registry.clone())))
+            .add_service(CrdtServer::new(CrdtServerImpl(entity_registry.clone())))
+            .add_service(StatelessFunctionServer::new(StatelessFunctionServerImpl(entity_registry)));
+        Ok(router)
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), ServeError> {
+        self.router()?.serve(addr).await?;
+        Ok(())
+    }
+}
+
+// Either the service is invalid or the server couldn't run
+#[derive(Debug)]
+pub enum ServeError {
+    Descriptor(DescriptorError),
+    Transport(tonic::transport::Error),
+}
+
+impl std::fmt::Display for ServeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServeError::Descriptor(err) => write!(f, "Invalid Cloudstate service: {}", err),
+            ServeError::Transport(err) => write!(f, "Cloudstate server error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServeError::Descriptor(err) => Some(err),
+            ServeError::Transport(err) => Some(err),
+        }
+    }
+}
+
+impl From<DescriptorError> for ServeError {
+    fn from(err: DescriptorError) -> Self {
+        ServeError::Descriptor(err)
+    }
+}
+
+impl From<tonic::transport::Error> for ServeError {
+    fn from(err: tonic::transport::Error) -> Self {
+        ServeError::Transport(err)
+    }
+}
+
+pub(crate) fn service_info(service_name: &str, service_version: &str) -> ServiceInfo {
+    ServiceInfo {
+        service_name: service_name.to_owned(),
+        service_version: service_version.to_owned(),
+        service_runtime: SERVICE_RUNTIME.to_owned(),
+        support_library_name: SUPPORT_LIBRARY_NAME.to_owned(),
+        support_library_version: SUPPORT_LIBRARY_VERSION.to_owned(),
+    }
+}
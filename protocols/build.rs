@@ -8,6 +8,8 @@ fn main() {
         .compile(&[
             "protocol/cloudstate/entity.proto",
             "protocol/cloudstate/event_sourced.proto",
+            "protocol/cloudstate/crdt.proto",
+            "protocol/cloudstate/function.proto",
         ], &[
             "protocol",
         ])
@@ -29,29 +31,12 @@ fn main() {
         .compile(&[
             "example/shoppingcart/persistence/domain.proto",
             "example/shoppingcart/shoppingcart.proto",
+            "example/crdts/crdt-example.proto",
+            "example/functions/echo.proto",
         ], &[
             "example",
             "frontend",
         ])
         .expect("failed to compile protos");
-    //TODO implement a custom ServiceGenerator to generate service specific command types with access to package name for unmarshaling code generation.
-
-    generate_example_file_descriptor_set();
-}
-
-fn generate_example_file_descriptor_set() {
-    let protoc = protoc::Protoc::from_env_path();
-    protoc.check().unwrap();
-    protoc.write_descriptor_set(protoc::DescriptorSetOutArgs {
-        out: "src/shoppingcart.desc",
-        includes: &[
-            "example",
-            "frontend",
-        ],
-        input: &[
-            "example/shoppingcart/persistence/domain.proto",
-            "example/shoppingcart/shoppingcart.proto",
-        ],
-        include_imports: true,
-    }).unwrap();
+    // The command and response types of the services are generated by cloudstate-build in the crate implementing the entities.
 }
@@ -1,15 +1,17 @@
+// tonic-build generates stream associated types in lower camel case, e.g. `handleStream`
+#[allow(non_camel_case_types)]
 pub mod protocol {
     pub mod cloudstate {
         include!("protocol/cloudstate.rs");
         pub mod eventsourced {
             include!("protocol/cloudstate.eventsourced.rs");
         }
-    }
-}
-
-pub mod example {
-    pub fn shopping_cart_descriptor_set() -> &'static [u8] {
-        include_bytes!("shoppingcart.desc")
+        pub mod crdt {
+            include!("protocol/cloudstate.crdt.rs");
+        }
+        pub mod function {
+            include!("protocol/cloudstate.function.rs");
+        }
     }
 }
 
@@ -22,4 +24,12 @@ pub mod prost_example {
             include!("prost_example/shoppingcart/com.example.shoppingcart.persistence.rs");
         }
     }
+
+    pub mod crdts {
+        include!("prost_example/shoppingcart/com.example.crdts.rs");
+    }
+
+    pub mod functions {
+        include!("prost_example/shoppingcart/com.example.functions.rs");
+    }
 }
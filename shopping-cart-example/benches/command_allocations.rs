@@ -0,0 +1,115 @@
+// Counts the heap allocations made while the shopping cart entity handles a command,
+// from the payload received by the server to the payloads sent back to the proxy.
+// Run with `cargo bench -p shopcart-example --bench command_allocations`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use prost::Message;
+use protocols::prost_example::shoppingcart::{AddLineItem, RemoveLineItem, GetShoppingCart};
+use cloudstate_core::eventsourced::{EventSourcedEntityHandler, CommandMetadata, SnapshotPolicy, NeverSnapshot, EveryNEvents};
+use shopcart_example::ShoppingCartEntity;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: usize = 1000;
+
+// A command as the server gets it from the proxy
+struct Command {
+    name: &'static str,
+    payload: prost_types::Any,
+}
+
+fn command<M: Message>(name: &'static str, message_name: &str, message: &M) -> Command {
+    let mut value = vec![];
+    message.encode(&mut value).unwrap();
+    Command {
+        name,
+        payload: prost_types::Any {
+            type_url: format!("type.googleapis.com/com.example.shoppingcart.{}", message_name),
+            value,
+        },
+    }
+}
+
+// Handles the command the same way the server does, up to the payloads of the reply
+async fn handle(entity: &mut (dyn EventSourcedEntityHandler + Send + Sync), command: &Command, sequence: i64, snapshot_policy: &dyn SnapshotPolicy) -> usize {
+    let metadata = CommandMetadata {
+        entity_id: "cart",
+        command_id: 1,
+        command_name: command.name,
+        sequence,
+    };
+    let response = entity.command_received(&command.payload.type_url, &command.payload.value, metadata, snapshot_policy).await;
+    let events: Vec<_> = response.events.into_iter()
+        .map(|(type_url, value)| prost_types::Any { type_url, value })
+        .collect();
+    let snapshot = response.snapshot.map(|(type_url, value)| prost_types::Any { type_url, value });
+    events.len() + snapshot.map_or(0, |_| 1)
+}
+
+fn bench(name: &str, snapshot_policy: &dyn SnapshotPolicy, commands: &[Command]) {
+    let mut runtime = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+    let mut entity: Box<dyn EventSourcedEntityHandler + Send + Sync> = Box::new(ShoppingCartEntity::default());
+
+    let (allocations, bytes) = runtime.block_on(async {
+        let mut sequence = 0;
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+        for _ in 0..ITERATIONS {
+            for command in commands {
+                sequence += handle(&mut *entity, command, sequence, snapshot_policy).await as i64;
+            }
+        }
+        (ALLOCATIONS.load(Ordering::Relaxed) - allocations, ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes)
+    });
+
+    let count = (ITERATIONS * commands.len()) as f64;
+    println!("{:<24} {:>8.1} allocations/command {:>10.1} bytes/command", name, allocations as f64 / count, bytes as f64 / count);
+}
+
+fn add_item() -> Command {
+    command("AddItem", "AddLineItem", &AddLineItem {
+        user_id: "cart".to_owned(),
+        product_id: "soap".to_owned(),
+        name: "Soap".to_owned(),
+        quantity: 1,
+    })
+}
+
+fn remove_item() -> Command {
+    command("RemoveItem", "RemoveLineItem", &RemoveLineItem {
+        user_id: "cart".to_owned(),
+        product_id: "soap".to_owned(),
+    })
+}
+
+fn main() {
+    // The item is removed again, so the cart doesn't grow
+    bench("add and remove item", &NeverSnapshot, &[add_item(), remove_item()]);
+    bench("with snapshots", &EveryNEvents(1), &[add_item(), remove_item()]);
+    bench("get cart", &NeverSnapshot, &[command("GetCart", "GetShoppingCart", &GetShoppingCart { user_id: "cart".to_owned() })]);
+}
@@ -0,0 +1,20 @@
+fn main() {
+    // The messages are generated by the protocols crate, only the entity types of the services are generated here
+    cloudstate_build::configure()
+        .extern_path(".com.example.shoppingcart", "::protocols::prost_example::shoppingcart")
+        .extern_path(".com.example.crdts", "::protocols::prost_example::crdts")
+        .extern_path(".com.example.functions", "::protocols::prost_example::functions")
+        .compile(&[
+            "../protocols/example/shoppingcart/persistence/domain.proto",
+            "../protocols/example/shoppingcart/shoppingcart.proto",
+            "../protocols/example/crdts/crdt-example.proto",
+            "../protocols/example/functions/echo.proto",
+        ], &[
+            "../protocols/example",
+            "../protocols/frontend",
+        ])
+        .expect("failed to compile protos");
+
+    println!("cargo:rerun-if-changed=../protocols/example");
+    println!("cargo:rerun-if-changed=../protocols/frontend");
+}
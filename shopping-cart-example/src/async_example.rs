@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use tracing::debug;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use protocols::prost_example::shoppingcart::{
+    self, AddLineItem, RemoveLineItem,
+    persistence::{Cart, ItemAdded, ItemRemoved, LineItem},
+};
+use cloudstate_core::async_trait;
+use cloudstate_core::eventsourced::{AsyncEventSourcedEntity, CommandContext, CommandError, EntityCommandContext, Response};
+use crate::{ShoppingCartEvent, ShoppingCartSnapshot};
+use crate::shoppingcart_service::{ShoppingCartCommand, ShoppingCartResponse};
+
+// The stock of the products, it can only be read asynchronously
+pub struct Inventory {
+    stock: RwLock<BTreeMap<String, i32>>,
+}
+
+impl Inventory {
+
+    pub fn new(stock: BTreeMap<String, i32>) -> Inventory {
+        Inventory {
+            stock: RwLock::new(stock),
+        }
+    }
+
+    async fn in_stock(&self, product_id: &str) -> i32 {
+        self.stock.read().await.get(product_id).cloned().unwrap_or(0)
+    }
+}
+
+// Shopping cart which checks the inventory before adding an item
+pub struct AsyncShoppingCartEntity {
+    cart_id: String,
+    items: BTreeMap<String, LineItem>,
+    inventory: Arc<Inventory>,
+}
+
+impl AsyncShoppingCartEntity {
+
+    pub fn new(cart_id: &str, inventory: Arc<Inventory>) -> AsyncShoppingCartEntity {
+        AsyncShoppingCartEntity {
+            cart_id: cart_id.to_owned(),
+            items: BTreeMap::new(),
+            inventory,
+        }
+    }
+
+    async fn add_line(&self, context: &mut impl CommandContext<ShoppingCartEvent>, item: AddLineItem) -> Result<(), CommandError> {
+        debug!(cart_id = %self.cart_id, product_id = %item.product_id, "Handle command");
+        if item.quantity <= 0 {
+            return Err(CommandError::Rejected(format!("Cannot add negative quantity of to item {}", item.product_id)))
+        }
+        let in_stock = self.inventory.in_stock(&item.product_id).await;
+        let in_cart = self.items.get(&item.product_id).map_or(0, |v| v.quantity);
+        if in_cart + item.quantity > in_stock {
+            return Err(CommandError::Rejected(format!("Only {} of item {} in stock", in_stock, item.product_id)))
+        }
+        context.emit_event(ItemAdded {
+            item: Some(
+                LineItem {
+                    product_id: item.product_id,
+                    name: item.name,
+                    quantity: item.quantity,
+                }
+            )
+        });
+        Ok(())
+    }
+
+    fn remove_line(&self, context: &mut impl CommandContext<ShoppingCartEvent>, item: RemoveLineItem) -> Result<(), CommandError> {
+        debug!(cart_id = %self.cart_id, product_id = %item.product_id, "Handle command");
+        if !self.items.contains_key(&item.product_id) {
+            return Err(CommandError::Rejected(format!("Cannot remove item {} because it is not in the cart.", item.product_id)))
+        }
+        context.emit_event(ItemRemoved {
+            product_id: item.product_id,
+        });
+        Ok(())
+    }
+
+    fn cart(&self) -> shoppingcart::Cart {
+        shoppingcart::Cart {
+            items: self.items.values()
+                .map(|item| shoppingcart::LineItem {
+                    product_id: item.product_id.clone(),
+                    name: item.name.clone(),
+                    quantity: item.quantity,
+                }).collect()
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncEventSourcedEntity for AsyncShoppingCartEntity {
+
+    type Command = ShoppingCartCommand;
+    type Response = ShoppingCartResponse;
+
+    type Snapshot = ShoppingCartSnapshot;
+    type Event = ShoppingCartEvent;
+
+    fn handle_snapshot(&mut self, snapshot: Self::Snapshot) {
+        let ShoppingCartSnapshot(Cart { items }) = snapshot;
+
+        self.items = items.into_iter()
+            .map(|item| (item.product_id.clone(), item))
+            .collect();
+    }
+
+    async fn handle_command<C>(&self, command: Self::Command, context: &mut C) -> Result<Response<Self::Response>, CommandError>
+        where C: EntityCommandContext<Self> + Send
+    {
+        match command {
+            ShoppingCartCommand::AddItem(item) => self.add_line(context, item).await.map(|_| Response::EmptyReply),
+            ShoppingCartCommand::RemoveItem(item) => self.remove_line(context, item).map(|_| Response::EmptyReply),
+            ShoppingCartCommand::GetCart(_) => Ok(Response::Reply(ShoppingCartResponse::GetCart(self.cart()))),
+        }
+    }
+
+    fn handle_event(&mut self, event: Self::Event) {
+        match event {
+            ShoppingCartEvent::ItemAdded(ItemAdded { item: Some(item) }) => {
+                self.items.entry(item.product_id.clone())
+                    .or_insert(LineItem { quantity: 0, ..item.clone() })
+                    .quantity += item.quantity;
+            },
+            ShoppingCartEvent::ItemAdded(_) => {},
+            ShoppingCartEvent::ItemRemoved(item_removed) => {
+                self.items.remove(&item_removed.product_id);
+            },
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use protocols::prost_example::crdts::{UpdateCounter, Get, CounterValue, MutateSet, SetSize, SetValue, User, OnlineStatus};
+use prost::Message;
+use cloudstate_core::AnyMessage;
+use cloudstate_core_derive::AnyMessage;
+use cloudstate_core::crdt::{CrdtEntity, CrdtCommandContext, GCounter, StreamedResponse};
+use cloudstate_core::{CommandError, Response};
+
+// Commands
+#[derive(AnyMessage)]
+#[package="com.example.crdts"]
+pub enum CounterCommand {
+    // The PN-counter methods take the same messages
+    #[command = "IncrementGCounter"]
+    Increment(UpdateCounter),
+    #[command = "GetGCounter"]
+    Get(Get),
+    // The rest of the service isn't implemented by the example
+    #[no_from]
+    OtherUpdate(UpdateCounter),
+    #[no_from]
+    OtherGet(Get),
+    MutateSet(MutateSet),
+    User(User),
+}
+
+#[derive(AnyMessage)]
+#[package="com.example.crdts"]
+pub enum CounterReply {
+    Value(CounterValue),
+    SetSize(SetSize),
+    SetValue(SetValue),
+    OnlineStatus(OnlineStatus),
+}
+
+// A CRDT entity backed by a grow-only counter
+#[derive(Default)]
+pub struct GCounterEntity;
+
+impl CrdtEntity for GCounterEntity {
+
+    type Command = CounterCommand;
+    type Response = CounterReply;
+
+    type State = GCounter;
+
+    fn handle_command(&self, command: Self::Command, context: &mut impl CrdtCommandContext<Self::State>) -> Result<Response<Self::Response>, CommandError> {
+        match command {
+            CounterCommand::Increment(update) => {
+                if update.value < 0 {
+                    return Err(CommandError::Rejected(format!("Cannot decrement a GCounter by {}", update.value)));
+                }
+                let value = context.state_mut().increment(update.value as u64);
+                Ok(Response::Reply(CounterReply::Value(CounterValue { value: value as i64 })))
+            },
+            CounterCommand::Get(_) => {
+                // A streamed get lets the client watch the counter, see state_changed
+                context.accept_stream();
+                let value = context.state().map_or(0, |v| v.value());
+                Ok(Response::Reply(CounterReply::Value(CounterValue { value: value as i64 })))
+            },
+            CounterCommand::OtherUpdate(_) | CounterCommand::OtherGet(_) | CounterCommand::MutateSet(_) | CounterCommand::User(_) => {
+                Err(CommandError::Rejected("Only the GCounter is implemented by the example".to_owned()))
+            },
+        }
+    }
+    fn state_changed(&self, command: &Self::Command, state: Option<&Self::State>) -> StreamedResponse<Self::Response> {
+        match (command, state) {
+            (CounterCommand::Get(_), Some(state)) => {
+                StreamedResponse::Message(CounterReply::Value(CounterValue { value: state.value() as i64 }))
+            },
+            // There's nothing left to watch
+            (CounterCommand::Get(_), None) => StreamedResponse::End(None),
+            _ => StreamedResponse::NoMessage,
+        }
+    }
+}
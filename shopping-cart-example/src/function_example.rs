@@ -0,0 +1,49 @@
+use protocols::prost_example::functions::Text;
+use prost::Message;
+use cloudstate_core::AnyMessage;
+use cloudstate_core_derive::AnyMessage;
+use cloudstate_core::function::StatelessFunction;
+use cloudstate_core::{CommandError, Response};
+
+#[derive(AnyMessage)]
+#[package="com.example.functions"]
+pub enum EchoCommand {
+    Text(Text),
+}
+
+#[derive(AnyMessage)]
+#[package="com.example.functions"]
+pub enum EchoReply {
+    Text(Text),
+}
+
+pub struct EchoFunction;
+
+impl StatelessFunction for EchoFunction {
+
+    type Command = EchoCommand;
+    type Response = EchoReply;
+
+    fn handle_command(&self, command: Self::Command) -> Result<Response<Self::Response>, CommandError> {
+        let EchoCommand::Text(text) = command;
+        Ok(Response::Reply(EchoReply::Text(text)))
+    }
+
+    fn handle_streamed_in(&self, commands: Vec<Self::Command>) -> Result<Response<Self::Response>, CommandError> {
+        let texts: Vec<String> = commands.into_iter()
+            .map(|EchoCommand::Text(text)| text.text)
+            .collect();
+        Ok(Response::Reply(EchoReply::Text(Text { text: texts.join(" ") })))
+    }
+
+    fn handle_streamed_out(&self, command: Self::Command) -> Vec<Result<Response<Self::Response>, CommandError>> {
+        let EchoCommand::Text(text) = command;
+        text.text.split_whitespace()
+            .map(|word| Ok(Response::Reply(EchoReply::Text(Text { text: word.to_owned() }))))
+            .collect()
+    }
+
+    fn handle_streamed(&self, command: Self::Command) -> Vec<Result<Response<Self::Response>, CommandError>> {
+        vec![self.handle_command(command)]
+    }
+}
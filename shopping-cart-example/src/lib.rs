@@ -1,69 +1,60 @@
 
-use bytes::Bytes;
-use std::sync::Arc;
-use protocols::protocol::cloudstate::{
-    entity_discovery_server::EntityDiscoveryServer,
-    eventsourced::event_sourced_server::EventSourcedServer,
-};
-use tonic::transport::Server;
 use protocols::prost_example::{
     shoppingcart::{self, AddLineItem, RemoveLineItem, GetShoppingCart,
                    persistence::{Cart, ItemAdded, ItemRemoved, LineItem},},
+    functions::Text,
 };
 use prost::Message;
 use cloudstate_core::AnyMessage;
 use cloudstate_core_derive::AnyMessage;
-use cloudstate_core::eventsourced::{EntityRegistry, EventSourcedEntity, CommandContext, Response};
-use cloudstate_server::{EventSourcedServerImpl, EntityDiscoveryServerImpl};
+use cloudstate_core::registry::EntityRegistry;
+use cloudstate_core::eventsourced::{EventSourcedEntity, CommandError, EntityCommandContext, Response, Passivation};
+use cloudstate_server::{CloudstateService, ServeError};
 use std::collections::BTreeMap;
+use tracing::debug;
 
-pub async fn run_server(host_port: String) -> Result<(), tonic::transport::Error> {
-    let addr = host_port.parse().unwrap();
+pub mod crdt_example;
+pub mod function_example;
+pub mod async_example;
 
-    let mut registry = EntityRegistry::new();
-    registry.register_event_sourced_entity("com.example.shoppingcart.ShoppingCart", "shopping-cart", ShoppingCartEntity::default);
-    registry.register_event_sourced_entity("snapshot-every-time", "shopping-cart", || ShoppingCartEntity::new(1));
-    registry.register_event_sourced_entity("snapshot-every-second-time", "shopping-cart", || ShoppingCartEntity::new(2));
-    // registry.add_entity("shopcart2", ShoppingCartEntity::default);
-    // registry.add_entity_type("shopcart3", PhantomData::<ShoppingCartEntity>);
+// The commands and responses of the ShoppingCart service, generated by cloudstate-build
+pub mod shoppingcart_service {
+    include!(concat!(env!("OUT_DIR"), "/com.example.shoppingcart.rs"));
+}
+
+// The descriptor set of the example services with all their imports, generated by cloudstate-build
+include!(concat!(env!("OUT_DIR"), "/file_descriptor_set.rs"));
 
-    let entity_registry = Arc::new(registry);
-    let server = EventSourcedServerImpl(entity_registry.clone());
+use shoppingcart_service::{ShoppingCartCommand, ShoppingCartResponse, ShoppingCartHandler};
 
-    let discovery_server = EntityDiscoveryServerImpl {
-        descriptor_set: protocols::example::shopping_cart_descriptor_set().to_vec(),
-        entity_registry,
-    };
-    let discovery = EntityDiscoveryServer::new(discovery_server);
-    let eventsourced = EventSourcedServer::new(server);
+pub async fn run_server(host_port: String) -> Result<(), ServeError> {
+    let addr = host_port.parse().unwrap();
 
-    Server::builder()
-        .add_service(discovery)
-        .add_service(eventsourced)
+    service()
+        .registry(registry())
         .serve(addr).await?;
 
     Ok(())
 }
 
-// Commands
-#[package="com.example.shoppingcart"]
-#[derive(AnyMessage)]
-pub enum ShoppingCartCommand {
-    AddLine(AddLineItem),
-    RemoveLine(RemoveLineItem),
-    GetCart(GetShoppingCart),
-    // GetCart2(GetShoppingCart, impl Ctx<shoppingcart::Cart>), //TODO what if we encode response type in the command?
-    // GetCart2(GetShoppingCart, &mut shoppingcart::Cart), //TODO or this way
+// The entities of the example services
+pub fn registry() -> EntityRegistry {
+    let mut registry = EntityRegistry::new();
+    registry.register_event_sourced_entity("com.example.shoppingcart.ShoppingCart", "shopping-cart", |_| ShoppingCartEntity::default());
+    registry.register_crdt_entity("com.example.crdts.CrdtExample", crdt_example::GCounterEntity::default);
+    registry.register_stateless_function("com.example.functions.Echo", function_example::EchoFunction);
+    // registry.add_entity("shopcart2", ShoppingCartEntity::default);
+    // registry.add_entity_type("shopcart3", PhantomData::<ShoppingCartEntity>);
+    registry
 }
 
-#[derive(AnyMessage)]
-#[package="com.example.shoppingcart"]
-pub enum ShoppingCartReply {
-    Cart(shoppingcart::Cart),
+pub fn service() -> CloudstateService {
+    CloudstateService::new("shopping-cart", env!("CARGO_PKG_VERSION"))
+        .descriptor_set(FILE_DESCRIPTOR_SET)
 }
 
 // Events
-#[derive(AnyMessage)]
+#[derive(AnyMessage, PartialEq)]
 #[package="com.example.shoppingcart.persistence"]
 pub enum ShoppingCartEvent {
     ItemAdded(ItemAdded),
@@ -73,10 +64,9 @@ pub enum ShoppingCartEvent {
 // Snapshot
 #[derive(AnyMessage)]
 #[package="com.example.shoppingcart.persistence"]
-pub enum ShoppingCartSnapshot {
-    Snapshot(Cart),
-}
+pub struct ShoppingCartSnapshot(pub Cart);
 
+#[derive(Clone)]
 struct ItemValue {
     name: String,
     qty: i32,
@@ -84,42 +74,36 @@ struct ItemValue {
 
 type ItemId = String;
 
-#[derive(Default)]
+// The cart can't hold more than that quantity of a single item
+const MAX_ITEM_QUANTITY: i32 = 1000;
+
+#[derive(Default, Clone)]
 pub struct ShoppingCartEntity {
     items: BTreeMap<ItemId, ItemValue>,
-    snapshot_every: Option<u32>,
-}
-
-impl ShoppingCartEntity {
-
-    fn new(snapshot_every: u32) -> ShoppingCartEntity {
-        ShoppingCartEntity {
-            items: BTreeMap::new(),
-            snapshot_every: Some(snapshot_every),
-        }
-    }
 }
 
 impl EventSourcedEntity for ShoppingCartEntity {
 
     type Command = ShoppingCartCommand;
-    type Response = ShoppingCartReply;
+    // Each command has its own reply, see ShoppingCartHandler
+    type Response = ShoppingCartResponse;
 
     type Snapshot = ShoppingCartSnapshot;
     type Event = ShoppingCartEvent;
 
-    fn snapshot_every(&self) -> Option<u32> {
-        self.snapshot_every
+    // The events are applied on emit so that the item quantity can be checked against the new state
+    fn working_copy(&self) -> Option<Self> {
+        Some(self.clone())
     }
 
     fn take_snapshot(&self) -> Option<Self::Snapshot> {
-        Some(ShoppingCartSnapshot::Snapshot(self.cart_persistence()))
+        Some(ShoppingCartSnapshot(self.cart_persistence()))
     }
 
     fn handle_snapshot(&mut self, snapshot: Self::Snapshot) {
-        let ShoppingCartSnapshot::Snapshot(cart) = snapshot;
+        let cart = snapshot.0;
 
-        println!("Loading snapshot: {:?}", &cart);
+        debug!(items = cart.items.len(), "Loading snapshot");
 
         self.items.clear();
 
@@ -128,26 +112,34 @@ impl EventSourcedEntity for ShoppingCartEntity {
         }
     }
 
-    fn handle_command(&self, command: Self::Command, context: &mut impl CommandContext<Self::Event>) -> Result<Response<Self::Response>, String> {
-        match command {
-            ShoppingCartCommand::AddLine(item) => self.add_line(context, item).map(|_| Response::EmptyReply),
-            ShoppingCartCommand::RemoveLine(item) => self.remove_line(context, item).map(|_| Response::EmptyReply),
-            ShoppingCartCommand::GetCart(cart) => Ok(Response::Reply(self.get_cart(cart))),
-        }
+    fn on_init(&mut self, entity_id: &str) {
+        debug!(entity_id, items = self.items.len(), "Cart initialized");
+    }
+
+    fn on_recovered(&mut self, sequence: i64) {
+        debug!(sequence, items = self.items.len(), "Cart recovered");
+    }
+
+    fn on_passivate(&mut self, passivation: Passivation) {
+        debug!(?passivation, "Cart passivated");
+    }
+
+    fn handle_command(&self, command: Self::Command, context: &mut impl EntityCommandContext<Self>) -> Result<Response<Self::Response>, CommandError> {
+        command.dispatch(self, context)
     }
 
     fn handle_event(&mut self, event: Self::Event) {
         match event {
             ShoppingCartEvent::ItemAdded(item_added) => {
-                println!("Handle event: {:?}", item_added);
+                debug!("Handle ItemAdded event");
                 if let Some(LineItem { product_id, name, quantity }) = item_added.item {
-                    let mut item_val = self.items.entry(product_id)
+                    let item_val = self.items.entry(product_id)
                         .or_insert(ItemValue { name, qty: 0 });
                     item_val.qty += quantity;
                 }
             },
             ShoppingCartEvent::ItemRemoved(item_removed) => {
-                println!("Handle event: {:?}", item_removed);
+                debug!(product_id = %item_removed.product_id, "Handle ItemRemoved event");
                 self.items.remove(&item_removed.product_id);
             },
         }
@@ -155,48 +147,55 @@ impl EventSourcedEntity for ShoppingCartEntity {
 }
 
 
-impl ShoppingCartEntity {
+impl<C: EntityCommandContext<ShoppingCartEntity>> ShoppingCartHandler<C> for ShoppingCartEntity {
 
-    fn add_line(&self, context: &mut impl CommandContext<ShoppingCartEvent>, item: AddLineItem) -> Result<(), String> {
-        println!("Handle command: {:?}", item);
+    fn add_item(&self, item: AddLineItem, context: &mut C) -> Result<Response<()>, CommandError> {
+        debug!(product_id = %item.product_id, sequence = context.sequence(), "Handle command");
         if item.quantity <= 0 {
-            return Err(format!("Cannot add negative quantity of to item {}", item.product_id))
+            return Err(CommandError::Rejected(format!("Cannot add negative quantity of to item {}", item.product_id)))
         }
-        context.emit_event(
-            ShoppingCartEvent::ItemAdded(
-                ItemAdded { //TODO maybe implement auto-conversion for: ItemAdded -> ShoppingCartEvent::ItemAdded
-                    item: Some(
-                        LineItem {
-                            product_id: item.product_id,
-                            name: item.name,
-                            quantity: item.quantity,
-                        }
-                    )
+        let product_id = item.product_id.clone();
+        context.emit_event(ItemAdded {
+            item: Some(
+                LineItem {
+                    product_id: item.product_id,
+                    name: item.name,
+                    quantity: item.quantity,
                 }
             )
-        );
-        Ok(())
+        });
+        // The event has already been applied, it's rolled back on error
+        let qty = context.entity().items.get(&product_id).map_or(0, |v| v.qty);
+        if qty > MAX_ITEM_QUANTITY {
+            return Err(CommandError::Rejected(format!("Cannot have more than {} of item {}", MAX_ITEM_QUANTITY, product_id)))
+        }
+        Ok(Response::Reply(()))
     }
 
-    fn remove_line(&self, context: &mut impl CommandContext<ShoppingCartEvent>, item: RemoveLineItem) -> Result<(), String> {
-        println!("Handle command: {:?}", item);
+    fn remove_item(&self, item: RemoveLineItem, context: &mut C) -> Result<Response<()>, CommandError> {
+        debug!(product_id = %item.product_id, "Handle command");
         if !self.items.contains_key(&item.product_id) {
-            return Err(format!("Cannot remove item {} because it is not in the cart.", item.product_id))
+            return Err(CommandError::Rejected(format!("Cannot remove item {} because it is not in the cart.", item.product_id)))
         }
-        context.emit_event(
-            ShoppingCartEvent::ItemRemoved(
-                ItemRemoved { //TODO maybe implement auto-conversion for: ItemAdded -> ShoppingCartEvent::ItemAdded
-                    product_id: item.product_id,
-                }
-            )
-        );
-        Ok(())
+        // Lets the echo function know about the removed item once the command succeeds
+        let notification = function_example::EchoCommand::Text(Text {
+            text: format!("Removed {} from {}", item.product_id, context.entity_id()),
+        });
+        context.effect("com.example.functions.Echo", "Echo", &notification, false);
+
+        context.emit_event(ItemRemoved {
+            product_id: item.product_id,
+        });
+        Ok(Response::Reply(()))
     }
 
-    fn get_cart(&self, cart: GetShoppingCart) -> ShoppingCartReply {
-        println!("Handle command: {:?}", cart);
-        ShoppingCartReply::Cart(self.cart())
+    fn get_cart(&self, cart: GetShoppingCart, _context: &mut C) -> Result<Response<shoppingcart::Cart>, CommandError> {
+        debug!(user_id = %cart.user_id, "Handle command");
+        Ok(Response::Reply(self.cart()))
     }
+}
+
+impl ShoppingCartEntity {
 
     fn cart(&self) -> shoppingcart::Cart {
         shoppingcart::Cart {
@@ -1,7 +1,13 @@
 
 use shopcart_example::run_server;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
-async fn main() -> Result<(), tonic::transport::Error> {
+async fn main() -> Result<(), cloudstate_server::ServeError> {
+    // The log level is configured with RUST_LOG, e.g. RUST_LOG=cloudstate_server=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
     run_server("0.0.0.0:8088".to_owned()).await
 }
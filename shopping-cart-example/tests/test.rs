@@ -1,51 +1,300 @@
 
-use bytes::Bytes;
 use futures_util::stream;
 use protocols::protocol::cloudstate::{
-    Command, client_action::Action,
+    Command, ClientAction, StreamCancelled, client_action::Action,
     eventsourced::{
-        EventSourcedInit, EventSourcedStreamIn, EventSourcedStreamOut, EventSourcedSnapshot,
+        EventSourcedInit, EventSourcedEvent, EventSourcedStreamIn, EventSourcedStreamOut, EventSourcedSnapshot,
         event_sourced_client::EventSourcedClient,
         event_sourced_stream_in::{Message},
         event_sourced_stream_out,
         EventSourcedReply
     },
+    crdt::{
+        CrdtInit, CrdtStreamIn, CrdtStreamOut, CrdtReply, CrdtStreamedMessage, CrdtState, CrdtDelta, GCounterState, GCounterDelta, VoteDelta,
+        crdt_client::CrdtClient, crdt_stream_in, crdt_stream_out, crdt_state, crdt_delta, crdt_state_action,
+    },
+    function::{FunctionCommand, FunctionReply, function_reply, stateless_function_client::StatelessFunctionClient},
     entity_discovery_client::EntityDiscoveryClient, ProxyInfo
 };
 use protocols::prost_example::shoppingcart::{
-    AddLineItem, persistence::*,
+    self, AddLineItem, RemoveLineItem, GetShoppingCart, persistence::*,
 };
+use protocols::prost_example::crdts::{UpdateCounter, Get, CounterValue};
+use protocols::prost_example::functions::Text;
 use prost_types::Any;
 use tonic::{
     Streaming, transport::Channel,
 };
 use tokio::runtime::Runtime;
-use shopcart_example::run_server;
+use tokio::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use shopcart_example::{ShoppingCartEntity, async_example::{AsyncShoppingCartEntity, Inventory}};
+use shopcart_example::crdt_example::GCounterEntity;
+use cloudstate_core::registry::EntityRegistry;
+use cloudstate_core::{AnyMessage, MessageError};
+use cloudstate_core::crdt::{self, Crdt, GCounter, PNCounter};
+use cloudstate_core::eventsourced::{
+    SnapshotPolicy, SnapshotContext, NeverSnapshot, EveryNEvents, OnEventType, StateSizeThreshold,
+    EventSourcedEntity, EventSourcedEntityHandler, EntityCommandContext, CommandMetadata,
+    CommandError, EntityAction, Response, Passivation,
+};
+use shopcart_example::{ShoppingCartEvent, ShoppingCartSnapshot};
+use shopcart_example::shoppingcart_service::{ShoppingCartCommand, ShoppingCartResponse};
+use cloudstate_server::{CloudstateService, DescriptorError, ServeError};
 
 #[test]
 fn test() {
-    let host = "127.0.0.1";
-    let port = 8080;
-    let addr = format!("http://{}:{}", host, port);
-
     let mut rt = Runtime::new().unwrap();
 
     // Running the server for tests within the same process to make sure it's stopped
-    // when a test assertion fails
-    let bind_addr = format!("0.0.0.0:{}", port);
-    rt.spawn(run_server(bind_addr));
+    // when a test assertion fails.
+    // The listener is bound to any free port before the server is spawned, thus the clients can connect right away.
+    let listener = rt.block_on(TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))).unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap());
+    rt.spawn(run_test_server(listener));
 
     let mut entity_discovery_client = rt.block_on(EntityDiscoveryClient::connect(addr.clone()))
         .expect("Cannot start entity discovery client");
 
-    let mut event_sourced_client = rt.block_on(EventSourcedClient::connect(addr))
+    let mut event_sourced_client = rt.block_on(EventSourcedClient::connect(addr.clone()))
         .expect("Cannot start event sourced client");
 
+    let mut crdt_client = rt.block_on(CrdtClient::connect(addr.clone()))
+        .expect("Cannot start CRDT client");
+
+    let mut function_client = rt.block_on(StatelessFunctionClient::connect(addr.clone()))
+        .expect("Cannot start stateless function client");
+
     //TODO implement more scenarios
     rt.block_on(discovery_test(&mut entity_discovery_client));
+    rt.block_on(discovery_unsupported_entity_type_test(&mut entity_discovery_client));
+    rt.block_on(discovery_unsupported_protocol_test(&mut entity_discovery_client));
     rt.block_on(event_sourced_test(&mut event_sourced_client));
     rt.block_on(event_sourced_snapshot_every_time_test(&mut event_sourced_client));
     rt.block_on(event_sourced_snapshot_every_second_time_test(&mut event_sourced_client));
+    rt.block_on(event_sourced_side_effect_test(&mut event_sourced_client));
+    rt.block_on(event_sourced_rollback_test(&mut event_sourced_client));
+    rt.block_on(event_sourced_async_test(&mut event_sourced_client));
+    rt.block_on(event_sourced_decode_failure_test(&mut event_sourced_client));
+    rt.block_on(event_sourced_replay_snapshot_test(&mut event_sourced_client));
+    rt.block_on(event_sourced_sequence_gap_test(&mut event_sourced_client));
+    rt.block_on(event_sourced_streamed_command_test(&mut event_sourced_client));
+    rt.block_on(event_sourced_lifecycle_test(&mut event_sourced_client, &addr));
+    rt.block_on(crdt_gcounter_test(&mut crdt_client));
+    rt.block_on(crdt_streamed_command_test(&mut crdt_client));
+    rt.block_on(crdt_unknown_service_test(&mut crdt_client));
+    rt.block_on(crdt_negative_vote_test(&mut crdt_client));
+    rt.block_on(function_unary_test(&mut function_client));
+    rt.block_on(function_streamed_in_test(&mut function_client));
+    rt.block_on(function_streamed_out_test(&mut function_client));
+}
+
+// The example service along with the test fixtures for the same service
+async fn run_test_server(mut listener: TcpListener) -> Result<(), ServeError> {
+    let mut registry = shopcart_example::registry();
+    registry.register_event_sourced_entity("snapshot-every-time", "shopping-cart", |_| ShoppingCartEntity::default())
+        .set_snapshot_policy(EveryNEvents(1));
+    registry.register_event_sourced_entity("snapshot-every-second-time", "shopping-cart", |_| ShoppingCartEntity::default())
+        .set_snapshot_policy(EveryNEvents(2));
+    registry.register_event_sourced_entity("lifecycle-shopping-cart", "shopping-cart", LifecycleCartEntity::new);
+    let inventory = Arc::new(Inventory::new(vec![("soap33".to_owned(), 20)].into_iter().collect()));
+    registry.register_event_sourced_entity("async-shopping-cart", "shopping-cart", move |cart_id| AsyncShoppingCartEntity::new(cart_id, inventory.clone()));
+
+    shopcart_example::service()
+        .registry(registry)
+        .hidden_service("snapshot-every-time")
+        .hidden_service("snapshot-every-second-time")
+        .hidden_service("lifecycle-shopping-cart")
+        .hidden_service("async-shopping-cart")
+        .router()?
+        .serve_with_incoming(listener.incoming()).await?;
+    Ok(())
+}
+
+#[test]
+fn descriptor_validation_test() {
+    let service = |registry| {
+        CloudstateService::new("test", "0.1")
+            .descriptor_set(shopcart_example::FILE_DESCRIPTOR_SET)
+            .registry(registry)
+    };
+
+    let mut registry = EntityRegistry::new();
+    registry.register_crdt_entity("com.example.crdts.Unknown", GCounterEntity::default);
+    assert_eq!(service(registry).validate(), Err(DescriptorError::ServiceNotFound {
+        service_name: "com.example.crdts.Unknown".to_owned(),
+    }));
+
+    let mut registry = EntityRegistry::new();
+    registry.register_crdt_entity("com.example.crdts.Unknown", GCounterEntity::default);
+    assert_eq!(service(registry).hidden_service("com.example.crdts.Unknown").validate(), Ok(()));
+
+    let mut registry = EntityRegistry::new();
+    registry.register_crdt_entity("com.example.shoppingcart.ShoppingCart", GCounterEntity::default);
+    assert_eq!(service(registry).validate(), Err(DescriptorError::UnhandledCommand {
+        service_name: "com.example.shoppingcart.ShoppingCart".to_owned(),
+        method: "AddItem".to_owned(),
+        message_type: "com.example.shoppingcart.AddLineItem".to_owned(),
+    }));
+
+    let registry = EntityRegistry::new();
+    let invalid = CloudstateService::new("test", "0.1").descriptor_set(&[0xff]).registry(registry);
+    assert!(matches!(invalid.validate(), Err(DescriptorError::Decode(_))));
+
+    // An invalid service is never served
+    let served = Runtime::new().unwrap().block_on(invalid.serve("127.0.0.1:0".parse().unwrap()));
+    assert!(matches!(served, Err(ServeError::Descriptor(DescriptorError::Decode(_)))));
+}
+
+#[test]
+fn snapshot_policy_test() {
+    let event = |type_url: &str| (type_url.to_owned(), vec![]);
+    let added = "type.googleapis.com/com.example.shoppingcart.persistence.ItemAdded";
+    let removed = "type.googleapis.com/com.example.shoppingcart.persistence.ItemRemoved";
+    let mut take_snapshot = || Some(("type.googleapis.com/com.example.shoppingcart.persistence.Cart".to_owned(), vec![0; 10]));
+
+    let should_snapshot = |policy: &dyn SnapshotPolicy, previous_sequence: i64, events: &[(String, Vec<u8>)], take_snapshot: &mut dyn FnMut() -> Option<(String, Vec<u8>)>| {
+        policy.should_snapshot(&mut SnapshotContext::new(previous_sequence, events, take_snapshot))
+    };
+
+    assert!(!should_snapshot(&NeverSnapshot, 1, &[event(added)], &mut take_snapshot));
+
+    // A command emitting several events jumps over the multiple of N
+    assert!(should_snapshot(&EveryNEvents(2), 3, &[event(added), event(added)], &mut take_snapshot));
+    assert!(should_snapshot(&EveryNEvents(2), 3, &[event(added)], &mut take_snapshot));
+    assert!(!should_snapshot(&EveryNEvents(2), 4, &[event(added)], &mut take_snapshot));
+    assert!(!should_snapshot(&EveryNEvents(5), 0, &[event(added), event(added)], &mut take_snapshot));
+
+    let on_removed = OnEventType(vec![removed.to_owned()]);
+    assert!(should_snapshot(&on_removed, 0, &[event(added), event(removed)], &mut take_snapshot));
+    assert!(!should_snapshot(&on_removed, 0, &[event(added)], &mut take_snapshot));
+
+    assert!(should_snapshot(&StateSizeThreshold(10), 0, &[event(added)], &mut take_snapshot));
+    assert!(!should_snapshot(&StateSizeThreshold(11), 0, &[event(added)], &mut take_snapshot));
+    assert!(!should_snapshot(&StateSizeThreshold(0), 0, &[event(added)], &mut || None));
+}
+
+// An event which is encoded fine but can't be decoded, e.g. a hand-written decode with a typo in the type URL
+#[derive(PartialEq)]
+struct UndecodableEvent(ItemAdded);
+
+impl AnyMessage for UndecodableEvent {
+    fn decode(type_url: &str, _bytes: impl bytes::Buf) -> Result<Self, MessageError> {
+        Err(MessageError::unknown_type(type_url))
+    }
+
+    fn encode(&self) -> Result<(String, Vec<u8>), MessageError> {
+        let mut buf = vec![];
+        prost::Message::encode(&self.0, &mut buf).unwrap();
+        Ok(("type.googleapis.com/com.example.shoppingcart.persistence.ItemAdded".to_owned(), buf))
+    }
+}
+
+struct UndecodableEventEntity {
+    verify_events: bool,
+    handled_events: usize,
+}
+
+impl EventSourcedEntity for UndecodableEventEntity {
+    type Command = ShoppingCartCommand;
+    type Event = UndecodableEvent;
+    type Snapshot = ShoppingCartSnapshot;
+    type Response = ShoppingCartResponse;
+
+    fn handle_snapshot(&mut self, _snapshot: Self::Snapshot) {}
+
+    fn verify_events(&self) -> bool {
+        self.verify_events
+    }
+
+    fn handle_command(&self, _command: Self::Command, context: &mut impl EntityCommandContext<Self>) -> Result<Response<Self::Response>, CommandError> {
+        context.emit_event(UndecodableEvent(ItemAdded { item: None }));
+        Ok(Response::EmptyReply)
+    }
+
+    fn handle_event(&mut self, _event: Self::Event) {
+        self.handled_events += 1;
+    }
+}
+
+mod tagged {
+    use prost::Message;
+    use cloudstate_core::AnyMessage;
+    use cloudstate_core_derive::AnyMessage;
+
+    // prost encodes the entries of the map in the random order of the HashMap
+    #[derive(Clone, PartialEq, Message)]
+    pub struct ItemTagged {
+        #[prost(map = "string, string", tag = "1")]
+        pub tags: std::collections::HashMap<String, String>,
+    }
+
+    #[derive(AnyMessage, PartialEq)]
+    #[package = "com.example.shoppingcart.persistence"]
+    pub enum TaggedEvent {
+        ItemTagged(ItemTagged),
+    }
+}
+
+struct TaggedItemEntity;
+
+impl EventSourcedEntity for TaggedItemEntity {
+    type Command = ShoppingCartCommand;
+    type Event = tagged::TaggedEvent;
+    type Snapshot = ShoppingCartSnapshot;
+    type Response = ShoppingCartResponse;
+
+    fn handle_snapshot(&mut self, _snapshot: Self::Snapshot) {}
+
+    fn verify_events(&self) -> bool {
+        true
+    }
+
+    fn handle_command(&self, _command: Self::Command, context: &mut impl EntityCommandContext<Self>) -> Result<Response<Self::Response>, CommandError> {
+        let tags = (0..20).map(|i| (format!("tag{}", i), format!("value{}", i))).collect();
+        context.emit_event(tagged::ItemTagged { tags });
+        Ok(Response::EmptyReply)
+    }
+
+    fn handle_event(&mut self, _event: Self::Event) {}
+}
+
+#[test]
+fn event_round_trip_test() {
+    let mut rt = Runtime::new().unwrap();
+    let mut command = vec![];
+    prost::Message::encode(&GetShoppingCart { user_id: "cart".to_owned() }, &mut command).unwrap();
+
+    let mut handle_command = |entity: &mut UndecodableEventEntity| {
+        let metadata = CommandMetadata { entity_id: "cart", command_id: 1, command_name: "GetCart", sequence: 0 };
+        let type_url = "type.googleapis.com/com.example.shoppingcart.GetShoppingCart";
+        rt.block_on(EventSourcedEntityHandler::command_received(entity, type_url, &command, metadata, &NeverSnapshot))
+    };
+
+    // The emitted event is applied as is, it's never decoded
+    let mut entity = UndecodableEventEntity { verify_events: false, handled_events: 0 };
+    let response = handle_command(&mut entity);
+    assert!(matches!(response.action, EntityAction::EmptyReply));
+    assert_eq!(response.events.len(), 1);
+    assert_eq!(entity.handled_events, 1);
+
+    // The event wouldn't be replayed, so the command fails
+    let mut entity = UndecodableEventEntity { verify_events: true, handled_events: 0 };
+    let response = handle_command(&mut entity);
+    assert!(matches!(response.action, EntityAction::Failure(CommandError::Internal(_))));
+    assert!(response.events.is_empty());
+    assert_eq!(entity.handled_events, 0);
+
+    // The event decodes to the same map, whatever the order of its encoded entries
+    for _ in 0..10 {
+        let metadata = CommandMetadata { entity_id: "cart", command_id: 1, command_name: "GetCart", sequence: 0 };
+        let type_url = "type.googleapis.com/com.example.shoppingcart.GetShoppingCart";
+        let response = rt.block_on(EventSourcedEntityHandler::command_received(&mut TaggedItemEntity, type_url, &command, metadata, &NeverSnapshot));
+        assert!(matches!(response.action, EntityAction::EmptyReply));
+        assert_eq!(response.events.len(), 1);
+    }
 }
 
 async fn discovery_test(client: &mut EntityDiscoveryClient<Channel>) {
@@ -54,7 +303,7 @@ async fn discovery_test(client: &mut EntityDiscoveryClient<Channel>) {
         protocol_minor_version: 1,
         proxy_name: "test".to_owned(),
         proxy_version: "0.1".to_owned(),
-        supported_entity_types: vec!["cloudstate.eventsourced.EventSourced".to_owned()]
+        supported_entity_types: supported_entity_types(),
     };
 
     let entity_spec = client.discover(proxy_info).await.unwrap();
@@ -65,6 +314,66 @@ async fn discovery_test(client: &mut EntityDiscoveryClient<Channel>) {
     assert_eq!(entity.entity_type, "cloudstate.eventsourced.EventSourced");
     assert_eq!(entity.service_name, "com.example.shoppingcart.ShoppingCart");
     assert_eq!(entity.persistence_id, "shopping-cart");
+
+    let crdt_entity = message.entities.iter()
+        .find(|e| e.entity_type == "cloudstate.crdt.Crdt")
+        .expect("Expected CRDT entity");
+    assert_eq!(crdt_entity.service_name, "com.example.crdts.CrdtExample");
+
+    let function = message.entities.iter()
+        .find(|e| e.entity_type == "cloudstate.function.StatelessFunction")
+        .expect("Expected stateless function");
+    assert_eq!(function.service_name, "com.example.functions.Echo");
+
+    // Test fixtures aren't in the descriptor set, so the proxy isn't told about them
+    assert!(message.entities.iter().all(|e| e.service_name != "snapshot-every-time"));
+    assert_eq!(message.entities.len(), 3);
+
+    let service_info = message.service_info.as_ref().expect("Expected service info");
+    assert_eq!(service_info.service_name, "shopping-cart");
+    assert_eq!(service_info.service_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(service_info.support_library_name, "cloudstate");
+    assert_eq!(service_info.support_library_version, "0.1.0");
+    assert!(service_info.service_runtime.starts_with("rustc "), "Unexpected runtime {}", service_info.service_runtime);
+}
+
+fn supported_entity_types() -> Vec<String> {
+    vec![
+        "cloudstate.eventsourced.EventSourced".to_owned(),
+        "cloudstate.crdt.Crdt".to_owned(),
+        "cloudstate.function.StatelessFunction".to_owned(),
+    ]
+}
+
+async fn discovery_unsupported_entity_type_test(client: &mut EntityDiscoveryClient<Channel>) {
+    let proxy_info = ProxyInfo {
+        protocol_major_version: 0,
+        protocol_minor_version: 1,
+        proxy_name: "test".to_owned(),
+        proxy_version: "0.1".to_owned(),
+        supported_entity_types: vec!["cloudstate.eventsourced.EventSourced".to_owned()]
+    };
+
+    let status = client.discover(proxy_info).await.expect_err("Expected discovery to fail");
+
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    assert!(status.message().contains("com.example.crdts.CrdtExample (cloudstate.crdt.Crdt)"), "Unexpected message {}", status.message());
+    assert!(status.message().contains("com.example.functions.Echo (cloudstate.function.StatelessFunction)"), "Unexpected message {}", status.message());
+}
+
+async fn discovery_unsupported_protocol_test(client: &mut EntityDiscoveryClient<Channel>) {
+    let proxy_info = ProxyInfo {
+        protocol_major_version: 1,
+        protocol_minor_version: 0,
+        proxy_name: "test".to_owned(),
+        proxy_version: "0.1".to_owned(),
+        supported_entity_types: supported_entity_types(),
+    };
+
+    let status = client.discover(proxy_info).await.expect_err("Expected discovery to fail");
+
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    assert_eq!(status.message(), "Proxy test 0.1 implements protocol version 1.0 but this library requires 0.1 or a later minor version");
 }
 
 async fn event_sourced_test(client: &mut EventSourcedClient<Channel>) {
@@ -99,6 +408,380 @@ async fn event_sourced_test(client: &mut EventSourcedClient<Channel>) {
     assert_eq!(inbound.message().await.unwrap(), None);
 }
 
+async fn event_sourced_side_effect_test(client: &mut EventSourcedClient<Channel>) {
+
+    let init_test_msg = InitTestMsg::new("com.example.shoppingcart.ShoppingCart");
+    let remove_line_item = RemoveLineItem {
+        user_id: "user_id".to_owned(),
+        product_id: "soap33".to_owned(),
+    };
+    let remove_command = Command {
+        entity_id: "shopcart_entity_id".to_string(),
+        id: 57,
+        name: "RemoveItem".to_string(),
+        payload: Some(remove_line_item.to_any("type.googleapis.com/com.example.shoppingcart.RemoveLineItem")),
+        streamed: false,
+    };
+
+    let requests = msgs_to_stream_in(
+        vec![
+            Message::Init(init_test_msg.event_sourced_init),
+            Message::Command(remove_command),
+        ]
+    );
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    {
+        let reply = inbound.expect_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, 57);
+        assert_eq!(reply.events.len(), 1);
+
+        assert_eq!(reply.side_effects.len(), 1);
+        let side_effect = &reply.side_effects[0];
+        assert_eq!(side_effect.service_name, "com.example.functions.Echo");
+        assert_eq!(side_effect.command_name, "Echo");
+        assert!(!side_effect.synchronous);
+        let payload = side_effect.payload.clone().expect("Expected side effect payload");
+        assert_eq!(payload.type_url, "type.googleapis.com/com.example.functions.Text");
+        assert_eq!(payload.decode::<Text>().expect("Expected Text").text, "Removed soap33 from shopcart_entity_id");
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+async fn event_sourced_rollback_test(client: &mut EventSourcedClient<Channel>) {
+
+    let init_test_msg = InitTestMsg::new("com.example.shoppingcart.ShoppingCart");
+    // The snapshot already has 12 of this item, so it's over the limit once the event is applied
+    let add_line_item = AddLineItem {
+        user_id: "user_id".to_owned(),
+        product_id: "soap33".to_owned(),
+        name: "soap".to_owned(),
+        quantity: 995,
+    };
+    let add_command = Command {
+        entity_id: "shopcart_entity_id".to_string(),
+        id: 58,
+        name: "AddItem".to_string(),
+        payload: Some(add_line_item.to_any("type.googleapis.com/com.example.shoppingcart.AddLineItem")),
+        streamed: false,
+    };
+    let get_command = Command {
+        entity_id: "shopcart_entity_id".to_string(),
+        id: 59,
+        name: "GetCart".to_string(),
+        payload: Some(GetShoppingCart { user_id: "user_id".to_owned() }
+            .to_any("type.googleapis.com/com.example.shoppingcart.GetShoppingCart")),
+        streamed: false,
+    };
+
+    let requests = msgs_to_stream_in(
+        vec![
+            Message::Init(init_test_msg.event_sourced_init),
+            Message::Command(add_command),
+            Message::Command(get_command),
+        ]
+    );
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    {
+        let reply = inbound.expect_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, 58);
+        assert!(reply.events.is_empty());
+        match reply.client_action.and_then(|v| v.action) {
+            Some(Action::Failure(failure)) => assert_eq!(failure.description, "Cannot have more than 1000 of item soap33"),
+            _ => panic!("Expected Failure"),
+        }
+    }
+
+    {
+        let reply = inbound.expect_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, 59);
+        let cart = reply.reply_payload().expect("Expected Action Reply")
+            .decode::<shoppingcart::Cart>().expect("Expected Cart");
+        assert_eq!(cart.items.len(), 1);
+        assert_eq!(cart.items[0].product_id, "soap33");
+        assert_eq!(cart.items[0].quantity, 12);
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+async fn event_sourced_async_test(client: &mut EventSourcedClient<Channel>) {
+
+    // The snapshot has 12 of soap33 and there are 20 of them in stock
+    let init_test_msg = InitTestMsg::new("async-shopping-cart");
+    let add_command = |id: i64| {
+        let add_line_item = AddLineItem {
+            user_id: "user_id".to_owned(),
+            product_id: "soap33".to_owned(),
+            name: "soap".to_owned(),
+            quantity: 5,
+        };
+        Command {
+            entity_id: "shopcart_entity_id".to_string(),
+            id,
+            name: "AddItem".to_string(),
+            payload: Some(add_line_item.to_any("type.googleapis.com/com.example.shoppingcart.AddLineItem")),
+            streamed: false,
+        }
+    };
+
+    let requests = msgs_to_stream_in(
+        vec![
+            Message::Init(init_test_msg.event_sourced_init),
+            Message::Command(add_command(60)),
+            Message::Command(add_command(61)),
+        ]
+    );
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    {
+        let reply = inbound.expect_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, 60);
+        reply.reply_payload().expect("Expected Action Reply");
+        assert_eq!(reply.events.len(), 1);
+    }
+
+    {
+        let reply = inbound.expect_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, 61);
+        assert!(reply.events.is_empty());
+        match reply.client_action.and_then(|v| v.action) {
+            Some(Action::Failure(failure)) => assert_eq!(failure.description, "Only 20 of item soap33 in stock"),
+            _ => panic!("Expected Failure"),
+        }
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+async fn event_sourced_decode_failure_test(client: &mut EventSourcedClient<Channel>) {
+
+    let init_test_msg = InitTestMsg::new("com.example.shoppingcart.ShoppingCart");
+    let unknown_command = Command {
+        entity_id: "shopcart_entity_id".to_string(),
+        id: 62,
+        name: "Unknown".to_string(),
+        payload: Some(Any {
+            type_url: "type.googleapis.com/com.example.shoppingcart.Unknown".to_owned(),
+            value: vec![],
+        }),
+        streamed: false,
+    };
+
+    let requests = msgs_to_stream_in(
+        vec![
+            Message::Init(init_test_msg.event_sourced_init),
+            Message::Command(unknown_command),
+        ]
+    );
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    // It's not the client's fault, so the whole entity fails rather than the command
+    match inbound.message().await.unwrap() {
+        Some(EventSourcedStreamOut { message: Some(event_sourced_stream_out::Message::Failure(failure)) }) => {
+            assert_eq!(failure.command_id, 62);
+            assert_eq!(failure.description, "Server error: couldn't decode the command type.googleapis.com/com.example.shoppingcart.Unknown");
+        },
+        other => panic!("Expected Failure, got {:?}", other),
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+async fn event_sourced_streamed_command_test(client: &mut EventSourcedClient<Channel>) {
+
+    let init_test_msg = InitTestMsg::new("com.example.shoppingcart.ShoppingCart");
+    let get_cart = GetShoppingCart { user_id: "shopcart_entity_id".to_owned() };
+    let streamed_command = Command {
+        entity_id: "shopcart_entity_id".to_string(),
+        id: 63,
+        name: "GetCart".to_string(),
+        payload: Some(get_cart.to_any("type.googleapis.com/com.example.shoppingcart.GetShoppingCart")),
+        streamed: true,
+    };
+    let command = Command {
+        streamed: false,
+        id: 64,
+        ..streamed_command.clone()
+    };
+
+    let requests = msgs_to_stream_in(
+        vec![
+            Message::Init(init_test_msg.event_sourced_init),
+            Message::Command(streamed_command),
+            Message::Command(command),
+        ]
+    );
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    // Only the command fails, the entity carries on
+    let reply = inbound.expect_reply().await.expect("Expected Reply");
+    assert_eq!(reply.command_id, 63);
+    match reply.client_action.and_then(|v| v.action) {
+        Some(Action::Failure(failure)) => {
+            assert_eq!(failure.description, "Streamed commands aren't supported by event sourced entities");
+        },
+        other => panic!("Expected Failure, got {:?}", other),
+    }
+
+    let reply = inbound.expect_reply().await.expect("Expected Reply");
+    assert_eq!(reply.command_id, 64);
+    assert!(reply_payload(&reply.client_action).is_some(), "Expected Action Reply");
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+// The lifecycle hooks called on the carts of the "lifecycle-shopping-cart" fixture, e.g. "cart_id passivate Closed"
+static CART_LIFECYCLE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Only counts the items of the cart, the hooks are recorded into CART_LIFECYCLE
+struct LifecycleCartEntity {
+    cart_id: String,
+    items: BTreeSet<String>,
+}
+
+impl LifecycleCartEntity {
+
+    fn new(cart_id: &str) -> LifecycleCartEntity {
+        LifecycleCartEntity {
+            cart_id: cart_id.to_owned(),
+            items: BTreeSet::new(),
+        }
+    }
+
+    fn record(&self, hook: String) {
+        CART_LIFECYCLE.lock().unwrap().push(format!("{} {}", self.cart_id, hook));
+    }
+}
+
+impl EventSourcedEntity for LifecycleCartEntity {
+    type Command = ShoppingCartCommand;
+    type Event = ShoppingCartEvent;
+    type Snapshot = ShoppingCartSnapshot;
+    type Response = ShoppingCartResponse;
+
+    fn handle_snapshot(&mut self, snapshot: Self::Snapshot) {
+        self.items = snapshot.0.items.into_iter().map(|item| item.product_id).collect();
+    }
+
+    fn on_init(&mut self, _entity_id: &str) {
+        self.record(format!("init with {} items", self.items.len()));
+    }
+
+    fn on_recovered(&mut self, sequence: i64) {
+        self.record(format!("recovered at {} with {} items", sequence, self.items.len()));
+    }
+
+    fn on_passivate(&mut self, passivation: Passivation) {
+        self.record(format!("passivate {:?}", passivation));
+    }
+
+    fn handle_command(&self, _command: Self::Command, _context: &mut impl EntityCommandContext<Self>) -> Result<Response<Self::Response>, CommandError> {
+        Ok(Response::EmptyReply)
+    }
+
+    fn handle_event(&mut self, event: Self::Event) {
+        match event {
+            ShoppingCartEvent::ItemAdded(ItemAdded { item: Some(item) }) => {
+                self.items.insert(item.product_id);
+            },
+            ShoppingCartEvent::ItemAdded(_) => {},
+            ShoppingCartEvent::ItemRemoved(item) => {
+                self.items.remove(&item.product_id);
+            },
+        }
+    }
+}
+
+// The lifecycle hooks recorded for the cart so far
+fn cart_lifecycle(cart_id: &str) -> Vec<String> {
+    CART_LIFECYCLE.lock().unwrap().iter()
+        .filter(|hook| hook.starts_with(&format!("{} ", cart_id)))
+        .cloned()
+        .collect()
+}
+
+async fn event_sourced_lifecycle_test(client: &mut EventSourcedClient<Channel>, addr: &str) {
+
+    let mut init_test_msg = InitTestMsg::new("lifecycle-shopping-cart");
+    init_test_msg.event_sourced_init.entity_id = "closed_cart".to_owned();
+    let get_cart = |cart_id: &str| Command {
+        entity_id: cart_id.to_owned(),
+        id: 65,
+        name: "GetCart".to_owned(),
+        payload: Some(GetShoppingCart { user_id: cart_id.to_owned() }.to_any("type.googleapis.com/com.example.shoppingcart.GetShoppingCart")),
+        streamed: false,
+    };
+
+    let requests = msgs_to_stream_in(
+        vec![
+            Message::Init(init_test_msg.event_sourced_init.clone()),
+            item_added_event(43, "replayed"),
+            Message::Command(get_cart("closed_cart")),
+        ]
+    );
+
+    let response = client.handle(requests).await.unwrap();
+    let mut inbound = response.into_inner();
+    inbound.expect_reply().await.expect("Expected Reply");
+    assert_eq!(inbound.message().await.unwrap(), None);
+
+    // The entity is passivated before the stream ends
+    assert_eq!(cart_lifecycle("closed_cart"), vec![
+        "closed_cart init with 1 items".to_owned(),
+        "closed_cart recovered at 43 with 2 items".to_owned(),
+        "closed_cart passivate Closed".to_owned(),
+    ]);
+
+    // The client goes away abruptly, its runtime is dropped while the requests are still open
+    init_test_msg.event_sourced_init.entity_id = "dropped_cart".to_owned();
+    let init = init_test_msg.event_sourced_init;
+    let addr = addr.to_owned();
+    std::thread::spawn(move || {
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let _open = rt.block_on(async move {
+            let mut client = EventSourcedClient::connect(addr).await.unwrap();
+            let (mut sender, requests) = tokio::sync::mpsc::channel(2);
+            sender.send(EventSourcedStreamIn { message: Some(Message::Init(init)) }).await.unwrap();
+            sender.send(EventSourcedStreamIn { message: Some(Message::Command(get_cart("dropped_cart"))) }).await.unwrap();
+            let mut inbound = client.handle(requests).await.unwrap().into_inner();
+            inbound.expect_reply().await.expect("Expected Reply");
+            (client, sender, inbound)
+        });
+    }).join().unwrap();
+
+    let mut lifecycle = cart_lifecycle("dropped_cart");
+    for _ in 0..100 {
+        if lifecycle.len() == 3 {
+            break;
+        }
+        tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+        lifecycle = cart_lifecycle("dropped_cart");
+    }
+    assert_eq!(lifecycle[..2], ["dropped_cart init with 1 items".to_owned(), "dropped_cart recovered at 42 with 1 items".to_owned()]);
+    // Either the server notices the reset of the stream or it only drops it
+    let passivation = lifecycle.get(2).expect("Expected the entity to be passivated");
+    assert!(passivation.starts_with("dropped_cart passivate Dropped") || passivation.starts_with("dropped_cart passivate Failed"),
+            "Unexpected passivation {}", passivation);
+}
+
 async fn event_sourced_snapshot_every_time_test(client: &mut EventSourcedClient<Channel>) {
 
     let init_test_msg = InitTestMsg::new("snapshot-every-time");
@@ -127,7 +810,7 @@ async fn event_sourced_snapshot_every_time_test(client: &mut EventSourcedClient<
         let item1 = &init_test_msg.cart.items[0];
 
         assert_eq!(cart.items.len(), 2);
-        assert!(cart.items.contains(&item1), "Expect containing initial snapshot item");
+        assert!(cart.items.contains(item1), "Expect containing initial snapshot item");
 
         let expected_item = add_one_item_command.add_line_item.to_line_item();
         assert!(cart.items.contains(&expected_item), "Expect containing added item");
@@ -172,7 +855,7 @@ async fn event_sourced_snapshot_every_second_time_test(client: &mut EventSourced
         let item1 = &init_test_msg.cart.items[0];
 
         assert_eq!(cart.items.len(), 2);
-        assert!(cart.items.contains(&item1), "Expect containing initial snapshot item");
+        assert!(cart.items.contains(item1), "Expect containing initial snapshot item");
 
         let mut expected_item = add_one_item_command.add_line_item.to_line_item().clone();
         expected_item.quantity *= 2; // the item has been added twice
@@ -182,6 +865,372 @@ async fn event_sourced_snapshot_every_second_time_test(client: &mut EventSourced
     assert_eq!(inbound.message().await.unwrap(), None);
 }
 
+fn item_added_event(sequence: i64, product_id: &str) -> Message {
+    let item_added = ItemAdded {
+        item: Some(LineItem {
+            product_id: product_id.to_owned(),
+            name: "Replayed".to_owned(),
+            quantity: 1,
+        }),
+    };
+    Message::Event(EventSourcedEvent {
+        sequence,
+        payload: Some(item_added.to_any("type.googleapis.com/com.example.shoppingcart.persistence.ItemAdded")),
+    })
+}
+
+async fn event_sourced_replay_snapshot_test(client: &mut EventSourcedClient<Channel>) {
+
+    let init_test_msg = InitTestMsg::new("snapshot-every-second-time");
+    let add_one_item_command = AddOneItemTestMsg::new();
+
+    // The replayed event moves the entity to the sequence 43, so the command's event is the 44th one
+    let requests = msgs_to_stream_in(
+        vec![
+            Message::Init(init_test_msg.event_sourced_init),
+            item_added_event(43, "replayed"),
+            Message::Command(add_one_item_command.command.clone()),
+        ]
+    );
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    {
+        let reply = inbound.expect_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, add_one_item_command.command.id);
+
+        let snapshot = reply.snapshot.expect("Expected snapshot");
+        let cart = snapshot.decode::<Cart>().expect("Expect Cart snapshot");
+        assert_eq!(cart.items.len(), 3);
+        assert!(cart.items.iter().any(|item| item.product_id == "replayed"), "Expect containing replayed item");
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+async fn event_sourced_sequence_gap_test(client: &mut EventSourcedClient<Channel>) {
+
+    let init_test_msg = InitTestMsg::new("com.example.shoppingcart.ShoppingCart");
+    let add_one_item_command = AddOneItemTestMsg::new();
+
+    let requests = msgs_to_stream_in(
+        vec![
+            Message::Init(init_test_msg.event_sourced_init),
+            item_added_event(43, "replayed"),
+            item_added_event(45, "skipped"),
+            Message::Command(add_one_item_command.command.clone()),
+        ]
+    );
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    match inbound.message().await.unwrap() {
+        Some(EventSourcedStreamOut { message: Some(event_sourced_stream_out::Message::Failure(failure)) }) => {
+            assert_eq!(failure.command_id, 0);
+            assert_eq!(failure.description, "Received event 45 while expecting event 44");
+        },
+        other => panic!("Expected Failure, got {:?}", other),
+    }
+
+    // The entity doesn't handle commands once its state is broken
+    match inbound.message().await.unwrap() {
+        Some(EventSourcedStreamOut { message: Some(event_sourced_stream_out::Message::Failure(failure)) }) => {
+            assert_eq!(failure.command_id, add_one_item_command.command.id);
+            assert_eq!(failure.description, "Received event 45 while expecting event 44");
+        },
+        other => panic!("Expected Failure, got {:?}", other),
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+#[test]
+fn crdt_counter_saturation_test() {
+    let mut counter = PNCounter::default();
+    assert_eq!(counter.increment(i64::MAX), i64::MAX);
+    assert_eq!(counter.increment(1), i64::MAX, "Expected the value to saturate at i64::MAX");
+    assert_eq!(counter.take_delta(), Some(crdt::CrdtDelta::PNCounter { change: i64::MAX }));
+
+    counter.apply_delta(crdt::CrdtDelta::PNCounter { change: 1 }).unwrap();
+    assert_eq!(counter.value(), i64::MAX);
+
+    let mut counter = PNCounter::default();
+    assert_eq!(counter.decrement(i64::MAX), -i64::MAX);
+    assert_eq!(counter.decrement(i64::MAX), i64::MIN, "Expected the value to saturate at i64::MIN");
+    assert_eq!(counter.decrement(i64::MIN), -1, "Expected decrementing by i64::MIN to saturate the negation");
+    assert_eq!(counter.take_delta(), Some(crdt::CrdtDelta::PNCounter { change: -1 }));
+
+    counter.apply_state(crdt::CrdtState::PNCounter { value: i64::MIN }).unwrap();
+    counter.apply_delta(crdt::CrdtDelta::PNCounter { change: -1 }).unwrap();
+    assert_eq!(counter.value(), i64::MIN);
+
+    let mut counter = GCounter::default();
+    counter.increment(u64::MAX);
+    assert_eq!(counter.increment(1), u64::MAX, "Expected the value to saturate at u64::MAX");
+}
+
+async fn crdt_gcounter_test(client: &mut CrdtClient<Channel>) {
+
+    let init = CrdtInit {
+        service_name: "com.example.crdts.CrdtExample".to_owned(),
+        entity_id: "counter_entity_id".to_owned(),
+        state: Some(CrdtState {
+            state: Some(crdt_state::State::Gcounter(GCounterState { value: 5 })),
+        }),
+    };
+
+    let increment = UpdateCounter {
+        key: "counter_entity_id".to_owned(),
+        value: 3,
+    };
+
+    let get = Get {
+        key: "counter_entity_id".to_owned(),
+    };
+
+    let requests = stream::iter(vec![
+        crdt_stream_in::Message::Init(init),
+        crdt_stream_in::Message::Command(Command {
+            entity_id: "counter_entity_id".to_owned(),
+            id: 1,
+            name: "IncrementGCounter".to_owned(),
+            payload: Some(increment.to_any("type.googleapis.com/com.example.crdts.UpdateCounter")),
+            streamed: false,
+        }),
+        crdt_stream_in::Message::Changed(CrdtDelta {
+            delta: Some(crdt_delta::Delta::Gcounter(GCounterDelta { increment: 2 })),
+        }),
+        crdt_stream_in::Message::Command(Command {
+            entity_id: "counter_entity_id".to_owned(),
+            id: 2,
+            name: "GetGCounter".to_owned(),
+            payload: Some(get.to_any("type.googleapis.com/com.example.crdts.Get")),
+            streamed: false,
+        }),
+        // The same message as IncrementGCounter but a different method
+        crdt_stream_in::Message::Command(Command {
+            entity_id: "counter_entity_id".to_owned(),
+            id: 3,
+            name: "UpdatePNCounter".to_owned(),
+            payload: Some(increment.to_any("type.googleapis.com/com.example.crdts.UpdateCounter")),
+            streamed: false,
+        }),
+    ].into_iter().map(|msg| CrdtStreamIn { message: Some(msg) }).collect::<Vec<_>>());
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    {
+        let reply = inbound.expect_crdt_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, 1);
+
+        let value = reply_payload(&reply.client_action).expect("Expected Action Reply")
+            .decode::<CounterValue>().expect("Expected CounterValue");
+        assert_eq!(value.value, 8);
+
+        let action = reply.state_action.and_then(|v| v.action).expect("Expected state action");
+        assert_eq!(action, crdt_state_action::Action::Update(CrdtDelta {
+            delta: Some(crdt_delta::Delta::Gcounter(GCounterDelta { increment: 3 })),
+        }));
+    }
+
+    {
+        let reply = inbound.expect_crdt_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, 2);
+
+        let value = reply_payload(&reply.client_action).expect("Expected Action Reply")
+            .decode::<CounterValue>().expect("Expected CounterValue");
+        assert_eq!(value.value, 10, "Expected the delta from the proxy to be applied");
+
+        assert!(reply.state_action.is_none(), "Expect no state action for a read-only command");
+    }
+
+    {
+        let reply = inbound.expect_crdt_reply().await.expect("Expected Reply");
+        assert_eq!(reply.command_id, 3);
+
+        match reply.client_action.and_then(|v| v.action) {
+            Some(Action::Failure(failure)) => assert_eq!(failure.description, "Only the GCounter is implemented by the example"),
+            other => panic!("Expected Failure, got {:?}", other),
+        }
+        assert!(reply.state_action.is_none(), "Expect no state action for a rejected command");
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+async fn crdt_streamed_command_test(client: &mut CrdtClient<Channel>) {
+
+    let init = CrdtInit {
+        service_name: "com.example.crdts.CrdtExample".to_owned(),
+        entity_id: "watched_counter_entity_id".to_owned(),
+        state: Some(CrdtState {
+            state: Some(crdt_state::State::Gcounter(GCounterState { value: 5 })),
+        }),
+    };
+
+    let watch = Command {
+        entity_id: "watched_counter_entity_id".to_owned(),
+        id: 1,
+        name: "GetGCounter".to_owned(),
+        payload: Some(Get { key: "watched_counter_entity_id".to_owned() }.to_any("type.googleapis.com/com.example.crdts.Get")),
+        streamed: true,
+    };
+    let increment = UpdateCounter {
+        key: "watched_counter_entity_id".to_owned(),
+        value: 3,
+    };
+
+    let requests = stream::iter(vec![
+        crdt_stream_in::Message::Init(init),
+        crdt_stream_in::Message::Command(watch),
+        crdt_stream_in::Message::Changed(CrdtDelta {
+            delta: Some(crdt_delta::Delta::Gcounter(GCounterDelta { increment: 2 })),
+        }),
+        crdt_stream_in::Message::Command(Command {
+            entity_id: "watched_counter_entity_id".to_owned(),
+            id: 2,
+            name: "IncrementGCounter".to_owned(),
+            payload: Some(increment.to_any("type.googleapis.com/com.example.crdts.UpdateCounter")),
+            streamed: false,
+        }),
+        crdt_stream_in::Message::StreamCancelled(StreamCancelled {
+            entity_id: "watched_counter_entity_id".to_owned(),
+            id: 1,
+        }),
+        // Nobody watches the counter anymore
+        crdt_stream_in::Message::Changed(CrdtDelta {
+            delta: Some(crdt_delta::Delta::Gcounter(GCounterDelta { increment: 1 })),
+        }),
+    ].into_iter().map(|msg| CrdtStreamIn { message: Some(msg) }).collect::<Vec<_>>());
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    let reply = inbound.expect_crdt_reply().await.expect("Expected Reply");
+    assert_eq!(reply.command_id, 1);
+    assert!(reply.streamed, "Expected the stream to be accepted");
+    let value = reply_payload(&reply.client_action).expect("Expected Action Reply")
+        .decode::<CounterValue>().expect("Expected CounterValue");
+    assert_eq!(value.value, 5);
+
+    // The delta from the proxy
+    let message = inbound.expect_crdt_streamed_message().await.expect("Expected StreamedMessage");
+    assert_eq!(message.command_id, 1);
+    assert!(!message.end_stream);
+    let value = reply_payload(&message.client_action).expect("Expected Action Reply")
+        .decode::<CounterValue>().expect("Expected CounterValue");
+    assert_eq!(value.value, 7);
+
+    // The command gets its reply before the watcher gets the change
+    let reply = inbound.expect_crdt_reply().await.expect("Expected Reply");
+    assert_eq!(reply.command_id, 2);
+    assert!(!reply.streamed);
+    let message = inbound.expect_crdt_streamed_message().await.expect("Expected StreamedMessage");
+    assert_eq!(message.command_id, 1);
+    let value = reply_payload(&message.client_action).expect("Expected Action Reply")
+        .decode::<CounterValue>().expect("Expected CounterValue");
+    assert_eq!(value.value, 10);
+
+    match inbound.message().await.unwrap() {
+        Some(CrdtStreamOut { message: Some(crdt_stream_out::Message::StreamCancelledResponse(response)) }) => {
+            assert_eq!(response.command_id, 1);
+            assert!(response.state_action.is_none());
+        },
+        other => panic!("Expected StreamCancelledResponse, got {:?}", other),
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+async fn crdt_unknown_service_test(client: &mut CrdtClient<Channel>) {
+
+    let init = CrdtInit {
+        service_name: "com.example.crdts.Unknown".to_owned(),
+        entity_id: "unknown_counter_entity_id".to_owned(),
+        state: None,
+    };
+
+    let requests = stream::iter(vec![
+        crdt_stream_in::Message::Init(init),
+        crdt_stream_in::Message::Command(Command {
+            entity_id: "unknown_counter_entity_id".to_owned(),
+            id: 1,
+            name: "GetGCounter".to_owned(),
+            payload: Some(Get { key: "unknown_counter_entity_id".to_owned() }.to_any("type.googleapis.com/com.example.crdts.Get")),
+            streamed: false,
+        }),
+    ].into_iter().map(|msg| CrdtStreamIn { message: Some(msg) }).collect::<Vec<_>>());
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    // The entity fails, then each of its commands fails
+    for command_id in 0..2 {
+        match inbound.message().await.unwrap() {
+            Some(CrdtStreamOut { message: Some(crdt_stream_out::Message::Failure(failure)) }) => {
+                assert_eq!(failure.command_id, command_id);
+                assert_eq!(failure.description, "Unknown service_name com.example.crdts.Unknown");
+            },
+            other => panic!("Expected Failure, got {:?}", other),
+        }
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}
+
+fn echo_command(name: &str, text: &str) -> FunctionCommand {
+    FunctionCommand {
+        service_name: "com.example.functions.Echo".to_owned(),
+        name: name.to_owned(),
+        payload: Some(Text { text: text.to_owned() }.to_any("type.googleapis.com/com.example.functions.Text")),
+    }
+}
+
+fn function_reply_text(reply: FunctionReply) -> Option<String> {
+    match reply.response {
+        Some(function_reply::Response::Reply(r)) => r.payload.and_then(|v| v.decode::<Text>()).map(|v| v.text),
+        _ => None,
+    }
+}
+
+async fn function_unary_test(client: &mut StatelessFunctionClient<Channel>) {
+    let reply = client.handle_unary(echo_command("Echo", "hello")).await.unwrap().into_inner();
+
+    assert_eq!(function_reply_text(reply), Some("hello".to_owned()));
+}
+
+async fn function_streamed_in_test(client: &mut StatelessFunctionClient<Channel>) {
+    let requests = stream::iter(vec![
+        echo_command("Concat", "hello"),
+        echo_command("Concat", "world"),
+    ]);
+
+    let reply = client.handle_streamed_in(requests).await.unwrap().into_inner();
+
+    assert_eq!(function_reply_text(reply), Some("hello world".to_owned()));
+}
+
+async fn function_streamed_out_test(client: &mut StatelessFunctionClient<Channel>) {
+    let response = client.handle_streamed_out(echo_command("Split", "hello brave new world")).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    let mut words = vec![];
+    while let Some(reply) = inbound.message().await.unwrap() {
+        words.push(function_reply_text(reply).expect("Expected Text reply"));
+    }
+
+    assert_eq!(words, vec!["hello", "brave", "new", "world"]);
+}
+
 trait AddLineItemExt {
     fn to_line_item(&self) -> LineItem;
 }
@@ -251,7 +1300,7 @@ impl AddOneItemTestMsg {
         let command = Command {
             entity_id: "shopcart_entity_id".to_string(),
             id: 56,
-            name: "command_name".to_string(),
+            name: "AddItem".to_string(),
             payload: Some(add_line_item.clone().to_any("type.googleapis.com/com.example.shoppingcart.AddLineItem")),
             streamed: false,
         };
@@ -280,23 +1329,54 @@ impl StreamingEventSourcedStreamOutExt for Streaming<EventSourcedStreamOut> {
     }
 }
 
+#[tonic::async_trait]
+trait StreamingCrdtStreamOutExt {
+    async fn expect_crdt_reply(&mut self) -> Option<CrdtReply>;
+    async fn expect_crdt_streamed_message(&mut self) -> Option<CrdtStreamedMessage>;
+}
+
+#[tonic::async_trait]
+impl StreamingCrdtStreamOutExt for Streaming<CrdtStreamOut> {
+    async fn expect_crdt_reply(&mut self) -> Option<CrdtReply> {
+        match self.message().await {
+            Ok(Some(CrdtStreamOut {
+                        message: Some(crdt_stream_out::Message::Reply(reply))
+                    })) => Some(reply),
+            _ => None,
+        }
+    }
+
+    async fn expect_crdt_streamed_message(&mut self) -> Option<CrdtStreamedMessage> {
+        match self.message().await {
+            Ok(Some(CrdtStreamOut {
+                        message: Some(crdt_stream_out::Message::StreamedMessage(message))
+                    })) => Some(message),
+            _ => None,
+        }
+    }
+}
+
 trait EventSourcedReplyExt {
     fn reply_payload(&self) -> Option<Any>;
 }
 
 impl EventSourcedReplyExt for EventSourcedReply {
     fn reply_payload(&self) -> Option<Any> {
-        self.client_action.iter()
-            .flat_map(|v| &v.action)
-            .flat_map(|v|
-                match v {
-                    Action::Reply(r) => r.payload.clone(),
-                    _ => None,
-                })
-            .last()
+        reply_payload(&self.client_action)
     }
 }
 
+fn reply_payload(client_action: &Option<ClientAction>) -> Option<Any> {
+    client_action.iter()
+        .flat_map(|v| &v.action)
+        .flat_map(|v|
+            match v {
+                Action::Reply(r) => r.payload.clone(),
+                _ => None,
+            })
+        .last()
+}
+
 trait AnyExt {
     fn decode<T>(self) -> Option<T>
         where T: prost::Message + Default;
@@ -305,8 +1385,7 @@ trait AnyExt {
 impl AnyExt for Any {
     fn decode<T>(self) -> Option<T> where T: prost::Message + Default {
         //TODO ideally it should take type_url into account, right now it only relies on T
-        let bytes = Bytes::from(self.value);
-        <T as prost::Message>::decode(bytes).ok()
+        <T as prost::Message>::decode(&self.value[..]).ok()
     }
 }
 
@@ -334,3 +1413,43 @@ fn msgs_to_stream_in<T>(msgs: T) -> impl tonic::IntoStreamingRequest<Message = E
         .collect();
     stream::iter(messages)
 }
+
+async fn crdt_negative_vote_test(client: &mut CrdtClient<Channel>) {
+
+    let init = CrdtInit {
+        service_name: "com.example.crdts.CrdtExample".to_owned(),
+        entity_id: "vote_entity_id".to_owned(),
+        state: None,
+    };
+
+    let requests = stream::iter(vec![
+        crdt_stream_in::Message::Init(init),
+        crdt_stream_in::Message::Changed(CrdtDelta {
+            delta: Some(crdt_delta::Delta::Vote(VoteDelta { self_vote: false, votes_for: -1, total_voters: 1 })),
+        }),
+        crdt_stream_in::Message::Command(Command {
+            entity_id: "vote_entity_id".to_owned(),
+            id: 1,
+            name: "GetGCounter".to_owned(),
+            payload: Some(Get { key: "vote_entity_id".to_owned() }.to_any("type.googleapis.com/com.example.crdts.Get")),
+            streamed: false,
+        }),
+    ].into_iter().map(|msg| CrdtStreamIn { message: Some(msg) }).collect::<Vec<_>>());
+
+    let response = client.handle(requests).await.unwrap();
+
+    let mut inbound = response.into_inner();
+
+    // The invalid delta fails the entity, then each of its commands fails
+    for command_id in 0..2 {
+        match inbound.message().await.unwrap() {
+            Some(CrdtStreamOut { message: Some(crdt_stream_out::Message::Failure(failure)) }) => {
+                assert_eq!(failure.command_id, command_id);
+                assert_eq!(failure.description, "Invalid Vote delta, negative votes_for: -1");
+            },
+            other => panic!("Expected Failure, got {:?}", other),
+        }
+    }
+
+    assert_eq!(inbound.message().await.unwrap(), None);
+}